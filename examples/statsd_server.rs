@@ -1,11 +1,22 @@
 extern crate metrics_distributor;
 
+use std::time::Duration;
+
 use metrics_distributor::SharedStore;
 use metrics_distributor::collectors::statsd::StatsdUdpListener;
+use metrics_distributor::forwarders::Forwarder;
+use metrics_distributor::forwarders::statsd::{StatsdForwarder, StatsdTransport};
 
 fn main() {
     let store = SharedStore::new();
 
+    let forwarder = StatsdForwarder::new("localhost:8125", StatsdTransport::Udp);
+    store.flush_every(Duration::from_secs(10), move |metrics| {
+        forwarder.forward(metrics)
+    });
+
     let listener = StatsdUdpListener::new(store);
-    listener.listen("localhost:9876");
+    if let Err(err) = listener.listen("localhost:9876") {
+        println!("{}", err);
+    }
 }
@@ -0,0 +1,393 @@
+//! Async versions of `StatsdUdpListener` and `StatsdTcpListener` built on
+//! tokio, for running the distributor inside an existing async service.
+//! They parse and record metrics exactly like the threaded listeners do.
+//!
+//! Requires the `tokio` feature.
+
+use std::future::{self, Future};
+use std::net::ToSocketAddrs;
+use std::str;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{mpsc, watch, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{delay_for, timeout};
+
+use super::listener::{
+    bind_udp,
+    decode_datagram,
+    first_addr,
+    handle_message,
+    ListenerError,
+    MAX_DATAGRAM_SIZE,
+    POLL_INTERVAL,
+};
+use super::statsd::parse_message;
+use super::super::SharedStore;
+
+/// Listens for StatsD UDP datagrams on a tokio runtime.
+pub struct AsyncStatsdUdpListener {
+    store: SharedStore,
+    parse_errors: AtomicUsize,
+    invalid_datagrams: AtomicUsize,
+    /// Largest datagram (in bytes) that will be accepted. Can't be more than
+    /// `MAX_DATAGRAM_SIZE`.
+    pub max_datagram_size: usize,
+    /// Size to request for the socket's receive buffer (`SO_RCVBUF`).
+    pub recv_buffer_size: Option<usize>,
+}
+
+impl AsyncStatsdUdpListener {
+    pub fn new(store: SharedStore) -> AsyncStatsdUdpListener {
+        AsyncStatsdUdpListener {
+            store,
+            parse_errors: AtomicUsize::new(0),
+            invalid_datagrams: AtomicUsize::new(0),
+            max_datagram_size: 8192,
+            recv_buffer_size: None,
+        }
+    }
+
+    /// Number of malformed metrics received so far.
+    pub fn parse_error_count(&self) -> usize {
+        self.parse_errors.load(Ordering::Relaxed)
+    }
+
+    /// Number of datagrams dropped so far because they were too big or
+    /// weren't valid UTF-8.
+    pub fn invalid_datagram_count(&self) -> usize {
+        self.invalid_datagrams.load(Ordering::Relaxed)
+    }
+
+    /// Receives datagrams forever (or until the socket can't be bound).
+    pub async fn listen<A>(&self, addr: A) -> Result<(), ListenerError>
+        where A: ToSocketAddrs {
+        self.listen_until(addr, future::pending()).await
+    }
+
+    /// Receives datagrams until `shutdown` completes. Every datagram received
+    /// before then will have been recorded when this returns.
+    pub async fn listen_until<A, F>(&self, addr: A, shutdown: F) -> Result<(), ListenerError>
+        where A: ToSocketAddrs, F: Future<Output=()> {
+        let socket = bind_udp(addr, self.recv_buffer_size, false).map_err(ListenerError::Bind)?;
+        let mut socket = UdpSocket::from_std(socket).map_err(ListenerError::Io)?;
+
+        let max_datagram_size = self.max_datagram_size.min(MAX_DATAGRAM_SIZE);
+        // One extra byte so that we can tell when a datagram didn't fit.
+        let mut buf = vec![0; max_datagram_size + 1];
+
+        tokio::pin!(shutdown);
+        loop {
+            let received = tokio::select! {
+                _ = &mut shutdown => return Ok(()),
+                received = socket.recv_from(&mut buf) => received,
+            };
+
+            let bytes_read = match received {
+                Ok((bytes_read, _)) => bytes_read,
+                Err(err) => {
+                    println!("Error receiving StatsD datagram: {:?}", err);
+                    continue
+                },
+            };
+
+            match decode_datagram(&buf, bytes_read, max_datagram_size) {
+                Some(message) => {
+                    let errors = handle_message(&self.store, parse_message, message);
+                    self.parse_errors.fetch_add(errors, Ordering::Relaxed);
+                },
+                None => {
+                    self.invalid_datagrams.fetch_add(1, Ordering::Relaxed);
+                },
+            }
+        }
+    } // fn listen_until
+} // impl AsyncStatsdUdpListener
+
+/// Listens for StatsD TCP connections on a tokio runtime. Each client is
+/// handled by its own task rather than its own thread.
+pub struct AsyncStatsdTcpListener {
+    store: SharedStore,
+    parse_errors: Arc<AtomicUsize>,
+    rejected_connections: AtomicUsize,
+    /// Most clients that can be connected at once; connections beyond that
+    /// are closed straight away. Unlimited if not set.
+    pub max_connections: Option<usize>,
+    /// How long clients can go without sending us anything before we'll
+    /// close their connection.
+    pub idle_timeout: Duration,
+}
+
+impl AsyncStatsdTcpListener {
+    pub fn new(store: SharedStore) -> AsyncStatsdTcpListener {
+        AsyncStatsdTcpListener {
+            store,
+            parse_errors: Arc::new(AtomicUsize::new(0)),
+            rejected_connections: AtomicUsize::new(0),
+            max_connections: Some(10_000),
+            idle_timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Number of malformed metrics received so far.
+    pub fn parse_error_count(&self) -> usize {
+        self.parse_errors.load(Ordering::Relaxed)
+    }
+
+    /// Number of connections closed so far because there were already
+    /// `max_connections` clients connected.
+    pub fn rejected_connection_count(&self) -> usize {
+        self.rejected_connections.load(Ordering::Relaxed)
+    }
+
+    /// Accepts connections forever (or until the socket can't be bound).
+    pub async fn listen<A>(&self, addr: A) -> Result<(), ListenerError>
+        where A: ToSocketAddrs {
+        self.listen_until(addr, future::pending()).await
+    }
+
+    /// Accepts connections until `shutdown` completes, then closes all of
+    /// the clients and waits for the lines they've sent to be recorded.
+    pub async fn listen_until<A, F>(&self, addr: A, shutdown: F) -> Result<(), ListenerError>
+        where A: ToSocketAddrs, F: Future<Output=()> {
+        let addr = first_addr(addr).map_err(ListenerError::Bind)?;
+        let mut listener = TcpListener::bind(addr).await.map_err(ListenerError::Bind)?;
+
+        let connections = self.max_connections.map(|max| Arc::new(Semaphore::new(max)));
+        // Clients stop when `stop` is dropped, and drop their `done` when
+        // they finish; once they're all gone `all_done` is closed.
+        let (stop, stopped) = watch::channel(());
+        let (done, mut all_done) = mpsc::channel::<()>(1);
+
+        tokio::pin!(shutdown);
+        loop {
+            let accepted = tokio::select! {
+                _ = &mut shutdown => break,
+                accepted = listener.accept() => accepted,
+            };
+
+            let (stream, _) = match accepted {
+                Ok(pair) => pair,
+                Err(err) => {
+                    // Most likely out of file descriptors; back off.
+                    println!("Error accepting StatsD connection: {:?}", err);
+                    delay_for(POLL_INTERVAL).await;
+                    continue
+                },
+            };
+
+            let permit = match connections {
+                Some(ref connections) => match connections.clone().try_acquire_owned() {
+                    Ok(permit) => Some(permit),
+                    Err(_) => {
+                        self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+                        continue
+                    },
+                },
+                None => None,
+            };
+
+            let client = Client {
+                store: self.store.clone(),
+                parse_errors: self.parse_errors.clone(),
+                idle_timeout: self.idle_timeout,
+                stopped: stopped.clone(),
+                _done: done.clone(),
+                _permit: permit,
+            };
+            tokio::spawn(client.run(stream));
+        }
+
+        drop(stop);
+        drop(done);
+        all_done.recv().await;
+        Ok(())
+    } // fn listen_until
+} // impl AsyncStatsdTcpListener
+
+/// State for a connected TCP client's task.
+struct Client {
+    store: SharedStore,
+    parse_errors: Arc<AtomicUsize>,
+    idle_timeout: Duration,
+    stopped: watch::Receiver<()>,
+    _done: mpsc::Sender<()>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Client {
+    async fn run(mut self, stream: TcpStream) {
+        let mut reader = BufReader::new(stream);
+        // Unlike `read_line`, `read_until` keeps what it has read in `line`
+        // if it's cancelled partway through a line.
+        let mut line = vec![];
+
+        loop {
+            let read = tokio::select! {
+                _ = wait_for_stop(&mut self.stopped) => None,
+                read = timeout(self.idle_timeout, reader.read_until(b'\n', &mut line)) => Some(read),
+            };
+
+            let read = match read {
+                Some(read) => read,
+                None => {
+                    // Keep whatever the client had sent that we've read
+                    // from the socket but not gotten to yet.
+                    line.extend_from_slice(reader.buffer());
+                    break
+                },
+            };
+
+            match read {
+                Err(_) => {
+                    // Timed out waiting for the client to send us anything.
+                    break
+                },
+                Ok(Err(err)) => {
+                    println!("Error reading StatsD line: {:?}", err);
+                    break
+                },
+                Ok(Ok(0)) => {
+                    // Close if there are no more bytes.
+                    break
+                },
+                Ok(Ok(_)) => self.record(&line),
+            }
+            line.clear();
+        }
+
+        // Don't lose a final line that was never terminated.
+        if !line.is_empty() {
+            self.record(&line);
+        }
+    }
+
+    /// Records one or more lines; lines that aren't valid UTF-8 count as
+    /// malformed.
+    fn record(&self, lines: &[u8]) {
+        let errors = match str::from_utf8(lines) {
+            Ok(lines) => handle_message(&self.store, parse_message, lines),
+            Err(_) => 1,
+        };
+        self.parse_errors.fetch_add(errors, Ordering::Relaxed);
+    }
+}
+
+/// Completes once the listener's `stop` sender has been dropped.
+async fn wait_for_stop(stopped: &mut watch::Receiver<()>) {
+    while stopped.recv().await.is_some() {}
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpListener, UdpSocket};
+    use std::time::Duration;
+
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpStream;
+    use tokio::runtime;
+    use tokio::sync::oneshot;
+    use tokio::time::delay_for;
+
+    use super::{AsyncStatsdTcpListener, AsyncStatsdUdpListener};
+    use super::super::super::SharedStore;
+    use super::super::super::metrics::{AggregatedMetrics, AggregatedMetricType, Dimension};
+
+    fn free_addr() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    fn run<F: std::future::Future>(future: F) -> F::Output {
+        runtime::Builder::new().basic_scheduler().enable_all().build().unwrap().block_on(future)
+    }
+
+    #[test]
+    fn udp_listener_records_datagrams() {
+        let store = SharedStore::new();
+        let listener = AsyncStatsdUdpListener::new(store.clone());
+        let addr = free_addr();
+
+        run(async {
+            let (stop, stopped) = oneshot::channel::<()>();
+            let sending = async {
+                delay_for(Duration::from_millis(50)).await;
+                let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+                client.send_to(b"foo:1|c\nfoo:2|c", addr).unwrap();
+                client.send_to(&[0xff], addr).unwrap();
+                delay_for(Duration::from_millis(50)).await;
+                stop.send(()).unwrap();
+            };
+
+            let listening = listener.listen_until(addr, async { stopped.await.unwrap() });
+            let (listened, _) = tokio::join!(listening, sending);
+            listened.unwrap();
+        });
+
+        assert_eq!(listener.invalid_datagram_count(), 1);
+        assert_eq!(store.flush(), AggregatedMetrics::with_metrics(vec![
+            (AggregatedMetricType::Count, Dimension::with_name("foo"), 3.0),
+        ]));
+    }
+
+    #[test]
+    fn tcp_listener_records_lines_from_clients() {
+        let store = SharedStore::new();
+        let mut listener = AsyncStatsdTcpListener::new(store.clone());
+        listener.max_connections = Some(1);
+        let addr = free_addr();
+
+        run(async {
+            let (stop, stopped) = oneshot::channel::<()>();
+            let sending = async {
+                delay_for(Duration::from_millis(50)).await;
+                let mut first = TcpStream::connect(addr).await.unwrap();
+                first.write_all(b"foo:1|c\nbar:2|c\n").await.unwrap();
+                delay_for(Duration::from_millis(50)).await;
+
+                let _second = TcpStream::connect(addr).await.unwrap();
+                delay_for(Duration::from_millis(50)).await;
+                stop.send(()).unwrap();
+            };
+
+            let listening = listener.listen_until(addr, async { stopped.await.unwrap() });
+            let (listened, _) = tokio::join!(listening, sending);
+            listened.unwrap();
+        });
+
+        assert_eq!(listener.rejected_connection_count(), 1);
+        assert_eq!(store.flush().len(), 2);
+    }
+
+    #[test]
+    fn tcp_listener_records_unterminated_lines_on_stop() {
+        let store = SharedStore::new();
+        let listener = AsyncStatsdTcpListener::new(store.clone());
+        let addr = free_addr();
+
+        run(async {
+            let (stop, stopped) = oneshot::channel::<()>();
+            let sending = async {
+                delay_for(Duration::from_millis(50)).await;
+                let mut client = TcpStream::connect(addr).await.unwrap();
+                client.write_all(b"foo:1|c\nbar:").await.unwrap();
+                delay_for(Duration::from_millis(50)).await;
+                client.write_all(b"2|c").await.unwrap();
+                delay_for(Duration::from_millis(50)).await;
+                stop.send(()).unwrap();
+                // Keep the client connected until the listener stops.
+                delay_for(Duration::from_millis(50)).await;
+            };
+
+            let listening = listener.listen_until(addr, async { stopped.await.unwrap() });
+            let (listened, _) = tokio::join!(listening, sending);
+            listened.unwrap();
+        });
+
+        let flushed = store.flush();
+        assert_eq!(flushed.len(), 2);
+        assert!(flushed.iter().any(|m| *m == (AggregatedMetricType::Count, Dimension::with_name("bar"), 2.0)));
+    }
+}
@@ -0,0 +1,72 @@
+use super::listener::{protocol_listener, Batch, TcpLineListener, UdpDatagramListener};
+use super::super::parsers::graphite::parse_metrics;
+
+protocol_listener! {
+    /// Listens on a TCP socket for Graphite plaintext metrics (what Carbon
+    /// listens for on port 2003). See `TcpLineListener` for its settings.
+    pub struct GraphiteTcpListener(TcpLineListener, parse_message);
+}
+
+protocol_listener! {
+    /// Listens for Graphite plaintext UDP datagrams, each holding one or
+    /// more lines. See `UdpDatagramListener` for its settings.
+    pub struct GraphiteUdpListener(UdpDatagramListener, parse_message);
+}
+
+/// Parses a Graphite message into the batch, returning the number of
+/// malformed lines in it for the listener to count.
+fn parse_message(batch: &mut Batch, message: &str) -> usize {
+    let parsed = parse_metrics(message);
+
+    batch.metrics.extend(parsed.metrics.iter().map(|m| m.to_standard_metric()));
+
+    parsed.errors.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::{TcpStream, UdpSocket};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{GraphiteTcpListener, GraphiteUdpListener};
+    use super::super::super::SharedStore;
+    use super::super::super::metrics::{AggregatedMetrics, AggregatedMetricType, Dimension};
+
+    #[test]
+    fn tcp_listener_keeps_newest_sample() {
+        let store = SharedStore::new();
+        let listener = GraphiteTcpListener::new(store.clone());
+
+        let handle = listener.spawn("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(handle.local_addr().unwrap()).unwrap();
+        client.write_all(b"load;host=web1 2 1500000060\nload;host=web1 1 1500000000\nload oops\n").unwrap();
+        client.flush().unwrap();
+        thread::sleep(Duration::from_millis(100));
+        handle.shutdown().unwrap();
+
+        assert_eq!(listener.parse_error_count(), 1);
+        assert_eq!(store.flush(), AggregatedMetrics::with_metrics(vec![
+            (AggregatedMetricType::Sample, Dimension::with_name("load").tagged("host", "web1"), 2.0),
+        ]));
+    }
+
+    #[test]
+    fn udp_listener_records_datagrams() {
+        let store = SharedStore::new();
+        let listener = GraphiteUdpListener::new(store.clone());
+
+        let handle = listener.spawn("127.0.0.1:0").unwrap();
+        let addr = handle.local_addr().unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"foo 1 1500000000\nbar 2", addr).unwrap();
+        client.send_to(&[0xff], addr).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        handle.shutdown().unwrap();
+
+        assert_eq!(listener.invalid_datagram_count(), 1);
+        assert_eq!(store.flush().len(), 2);
+    }
+}
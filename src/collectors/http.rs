@@ -1,15 +1,29 @@
+use iron::headers::ContentType;
+use iron::mime::{Mime, SubLevel, TopLevel};
 use iron::prelude::*;
 use iron::middleware::Handler;
 use iron::status::Status;
-use std::io::Read;
+use rustc_serialize::json::{Json, ToJson};
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+use std::str;
+use url::form_urlencoded;
 
 use super::super::SharedStore;
-use super::super::metrics::Metric;
+use super::super::metrics::{Dimension, Metric, Tags};
 use super::super::parsers::log_line::LogLineReader;
+use super::super::parsers::logplex::parse_frames;
 
 /// Accepts HTTP requests and reads lines from the body. Each line will be
 /// passed to its set of `readers` and any metrics collected by those readers
 /// will be recorded in the `store`.
+///
+/// Bodies from Heroku HTTPS log drains (which have a `Logplex-Msg-Count`
+/// header or an `application/logplex-1` content type) are split into their
+/// octet-counted frames instead, and each message is passed to the readers'
+/// `read_logplex_message` along with the `Logplex-Drain-Token`. If the
+/// framing is invalid or the number of messages doesn't match the header
+/// then nothing is recorded and the response is a `400`.
 pub struct LogDrainHandler {
     store: SharedStore,
     readers: Vec<Box<dyn LogLineReader>>,
@@ -24,6 +38,39 @@ impl LogDrainHandler {
             readers,
         }
     }
+
+    fn read_lines(&self, body: &str) -> Vec<Metric> {
+        let ref readers = self.readers;
+        let mut metrics: Vec<Metric> = vec![];
+
+        for line in body.lines() {
+            for reader in readers {
+                metrics.extend(reader.read(line))
+            }
+        }
+
+        metrics
+    }
+
+    fn read_logplex(&self, body: &str, msg_count: Option<&str>, drain_token: Option<&str>) -> Result<Vec<Metric>, String> {
+        let mut messages = parse_frames(body).map_err(|error| error.to_string())?;
+
+        if let Some(msg_count) = msg_count {
+            if msg_count.trim().parse() != Ok(messages.len()) {
+                return Err(format!("Logplex-Msg-Count is {:?} but the body has {} messages", msg_count, messages.len()))
+            }
+        }
+
+        let mut metrics: Vec<Metric> = vec![];
+        for message in messages.iter_mut() {
+            message.drain_token = drain_token;
+            for reader in self.readers.iter() {
+                metrics.extend(reader.read_logplex_message(message))
+            }
+        }
+
+        Ok(metrics)
+    }
 }
 
 impl Handler for LogDrainHandler {
@@ -37,17 +84,474 @@ impl Handler for LogDrainHandler {
             },
         }
 
-        let ref readers = self.readers;
-        let mut metrics: Vec<Metric> = vec![];
+        let msg_count = raw_header(req, "Logplex-Msg-Count");
+        let is_logplex = msg_count.is_some() || match req.headers.get::<ContentType>() {
+            Some(&ContentType(Mime(TopLevel::Application, SubLevel::Ext(ref sub_level), _))) => sub_level == "logplex-1",
+            _ => false,
+        };
 
-        for line in body.lines() {
-            for reader in readers {
-                metrics.extend(reader.read(line))
+        let metrics = if is_logplex {
+            match self.read_logplex(&body, msg_count, raw_header(req, "Logplex-Drain-Token")) {
+                Ok(metrics) => metrics,
+                Err(error) => return Ok(Response::with((Status::BadRequest, format!("{}\n", error)))),
             }
-        }
+        } else {
+            self.read_lines(&body)
+        };
 
         self.store.record(metrics);
 
         Ok(Response::with(Status::Created))
     }
 }
+
+fn raw_header<'a>(req: &'a Request, name: &str) -> Option<&'a str> {
+    req.headers.get_raw(name)
+        .and_then(|values| values.first())
+        .and_then(|value| str::from_utf8(value).ok())
+}
+
+/// Accepts a JSON array of metrics to record:
+///
+/// ```json
+/// [
+///   {"type": "count", "name": "jobs.done", "source": "worker.1", "tags": {"queue": "mail"}, "value": 3},
+///   {"type": "measure", "name": "jobs.duration", "value": 1.2}
+/// ]
+/// ```
+///
+/// `type` is one of `count` (the value must be a whole number), `measure`,
+/// `sample`, `set` (the value may be a string) or `distribution`. `source`
+/// and `tags` are optional.
+///
+/// Every valid item is recorded even if others aren't. The response is a
+/// `201` if everything was recorded, otherwise a `400`; either way its body
+/// says how many items were recorded and why the others weren't, eg.
+/// `{"recorded": 1, "errors": [{"index": 1, "error": "Missing name"}]}`.
+/// Bodies that are too large or aren't UTF-8 get the same shape of response,
+/// with an error that has no `index`.
+pub struct JsonBatchHandler {
+    /// Bodies larger than this many bytes are rejected with a `413` without
+    /// recording anything. Defaults to 25MB.
+    pub max_body_size: usize,
+    store: SharedStore,
+}
+
+impl JsonBatchHandler {
+    pub fn new(store: SharedStore) -> JsonBatchHandler {
+        JsonBatchHandler {
+            max_body_size: 25_000_000,
+            store,
+        }
+    }
+
+    /// Records the valid items in the batch and returns the response.
+    fn record(&self, body: &str) -> (Status, Json) {
+        let items = match Json::from_str(body) {
+            Ok(Json::Array(items)) => items,
+            Ok(_) => return batch_response(0, vec![(None, "Body must be a JSON array".to_owned())]),
+            Err(err) => return batch_response(0, vec![(None, format!("Invalid JSON: {}", err))]),
+        };
+
+        let mut metrics = vec![];
+        let mut errors = vec![];
+        for (index, item) in items.iter().enumerate() {
+            match parse_json_metric(item) {
+                Ok(metric) => metrics.push(metric),
+                Err(error) => errors.push((Some(index), error)),
+            }
+        }
+
+        let recorded = metrics.len();
+        self.store.record(metrics);
+        batch_response(recorded, errors)
+    }
+}
+
+impl Handler for JsonBatchHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let (status, json) = match read_body(&mut req.body, self.max_body_size) {
+            Ok(body) => self.record(&body),
+            Err(BodyError::Io(error)) => {
+                println!("{:?}", error);
+                return Err(IronError::new(error, Status::InternalServerError))
+            },
+            Err(BodyError::TooLarge) => {
+                let error = format!("Body is larger than {} bytes", self.max_body_size);
+                (Status::PayloadTooLarge, batch_response(0, vec![(None, error)]).1)
+            },
+            Err(BodyError::NotUtf8) => batch_response(0, vec![(None, "Body must be valid UTF-8".to_owned())]),
+        };
+
+        let mut response = Response::with((status, json.to_string()));
+        response.headers.set(ContentType::json());
+        Ok(response)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum BodyError {
+    Io(io::Error),
+    TooLarge,
+    NotUtf8,
+}
+
+/// Reads at most `max_size` bytes of UTF-8 from a request body.
+pub(crate) fn read_body<R: Read>(body: &mut R, max_size: usize) -> Result<String, BodyError> {
+    let mut bytes = vec![];
+    body.take(max_size as u64 + 1).read_to_end(&mut bytes).map_err(BodyError::Io)?;
+    if bytes.len() > max_size {
+        return Err(BodyError::TooLarge)
+    }
+
+    String::from_utf8(bytes).map_err(|_| BodyError::NotUtf8)
+}
+
+fn batch_response(recorded: usize, errors: Vec<(Option<usize>, String)>) -> (Status, Json) {
+    let status = if errors.is_empty() { Status::Created } else { Status::BadRequest };
+
+    let errors: Vec<Json> = errors.into_iter()
+        .map(|(index, error)| {
+            let mut object: BTreeMap<String, Json> = BTreeMap::new();
+            if let Some(index) = index {
+                object.insert("index".to_owned(), index.to_json());
+            }
+            object.insert("error".to_owned(), error.to_json());
+            object.to_json()
+        })
+        .collect();
+
+    let mut object: BTreeMap<String, Json> = BTreeMap::new();
+    object.insert("recorded".to_owned(), recorded.to_json());
+    object.insert("errors".to_owned(), Json::Array(errors));
+    (status, object.to_json())
+}
+
+fn parse_json_metric(item: &Json) -> Result<Metric, String> {
+    let object = item.as_object().ok_or("Expected an object")?;
+
+    let name = match object.get("name") {
+        Some(Json::String(name)) if !name.is_empty() => name,
+        Some(_) => return Err("Name must be a non-empty string".to_owned()),
+        None => return Err("Missing name".to_owned()),
+    };
+    let mut dim = Dimension::with_name(name);
+
+    match object.get("source") {
+        Some(Json::String(source)) => dim.source = Some(source.clone()),
+        Some(Json::Null) | None => {},
+        Some(_) => return Err("Source must be a string".to_owned()),
+    }
+
+    match object.get("tags") {
+        Some(Json::Object(tags)) => {
+            let mut parsed = Tags::new();
+            for (key, value) in tags.iter() {
+                match *value {
+                    Json::String(ref value) => { parsed.insert(key.clone(), value.clone()); },
+                    _ => return Err(format!("Tag {:?} must be a string", key)),
+                }
+            }
+            dim.tags = parsed;
+        },
+        Some(Json::Null) | None => {},
+        Some(_) => return Err("Tags must be an object".to_owned()),
+    }
+
+    let value = object.get("value").ok_or("Missing value")?;
+    let number = || match value.as_f64() {
+        Some(number) if number.is_finite() => Ok(number),
+        _ => Err("Value must be a number".to_owned()),
+    };
+
+    match object.get("type").and_then(|t| t.as_string()) {
+        Some("count") => match *value {
+            Json::I64(count) => Ok(Metric::Count(dim, count)),
+            Json::U64(count) if count <= i64::MAX as u64 => Ok(Metric::Count(dim, count as i64)),
+            Json::F64(count) if count.fract() == 0.0 && count.abs() < 9.0e18 => Ok(Metric::Count(dim, count as i64)),
+            _ => Err("Count value must be a whole number".to_owned()),
+        },
+        Some("measure") => Ok(Metric::Measure(dim, number()?)),
+        Some("sample") => Ok(Metric::Sample(dim, number()?)),
+        Some("distribution") => Ok(Metric::Distribution(dim, number()?)),
+        Some("set") => match *value {
+            Json::String(ref value) => Ok(Metric::Set(dim, value.clone())),
+            Json::I64(_) | Json::U64(_) | Json::F64(_) => Ok(Metric::Set(dim, value.to_string())),
+            _ => Err("Set value must be a string or a number".to_owned()),
+        },
+        Some(other) => Err(format!("Unknown type {:?}", other)),
+        None => Err("Missing type".to_owned()),
+    }
+}
+
+/// Accepts `application/x-www-form-urlencoded` bodies so that metrics can be
+/// submitted with nothing more than `curl -d`:
+///
+/// ```text
+/// count[jobs.done]=3&measure[jobs.duration]=1.2&sample[queue.size]=40&source=worker.1
+/// ```
+///
+/// Metrics are given as `count[name]` (a whole number), `measure[name]`,
+/// `sample[name]` or `set[name]`, and the same one may be given more than
+/// once. `source` and any `tag[key]=value` fields apply to every metric in
+/// the body.
+///
+/// If any of the body is invalid (including if it isn't UTF-8) then none of
+/// it is recorded and the response is a `400` saying why. Bodies that aren't
+/// form data get a `415`.
+pub struct FormBatchHandler {
+    /// Bodies larger than this many bytes are rejected with a `413` without
+    /// recording anything. Defaults to 1MB.
+    pub max_body_size: usize,
+    store: SharedStore,
+}
+
+impl FormBatchHandler {
+    pub fn new(store: SharedStore) -> FormBatchHandler {
+        FormBatchHandler {
+            max_body_size: 1_000_000,
+            store,
+        }
+    }
+
+    fn record(&self, body: &str) -> Result<(), String> {
+        let metrics = parse_form(body)?;
+        self.store.record(metrics);
+        Ok(())
+    }
+}
+
+impl Handler for FormBatchHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        match req.headers.get::<ContentType>() {
+            Some(&ContentType(Mime(TopLevel::Application, SubLevel::WwwFormUrlEncoded, _))) | None => {},
+            Some(_) => {
+                return Ok(Response::with((Status::UnsupportedMediaType, "Expected an application/x-www-form-urlencoded body\n")))
+            },
+        }
+
+        let body = match read_body(&mut req.body, self.max_body_size) {
+            Ok(body) => body,
+            Err(BodyError::Io(error)) => {
+                println!("{:?}", error);
+                return Err(IronError::new(error, Status::InternalServerError))
+            },
+            Err(BodyError::TooLarge) => {
+                return Ok(Response::with((Status::PayloadTooLarge, format!("Body is larger than {} bytes\n", self.max_body_size))))
+            },
+            Err(BodyError::NotUtf8) => {
+                return Ok(Response::with((Status::BadRequest, "Body must be valid UTF-8\n")))
+            },
+        };
+
+        match self.record(&body) {
+            Ok(()) => Ok(Response::with(Status::Created)),
+            Err(error) => Ok(Response::with((Status::BadRequest, format!("{}\n", error)))),
+        }
+    }
+}
+
+fn parse_form(body: &str) -> Result<Vec<Metric>, String> {
+    let mut source = None;
+    let mut tags = Tags::new();
+    let mut fields = vec![];
+
+    // `curl --data-binary @file` keeps the file's trailing newline.
+    for (key, value) in form_urlencoded::parse(body.trim().as_bytes()) {
+        if key == "source" {
+            source = Some(value.into_owned());
+            continue
+        }
+
+        let (kind, name) = split_form_key(&key).ok_or_else(|| format!("Unknown field {:?}", key))?;
+        if name.is_empty() {
+            return Err(format!("Missing name in {:?}", key))
+        }
+
+        if kind == "tag" {
+            tags.insert(name.to_owned(), value.into_owned());
+        } else {
+            fields.push((kind, name.to_owned(), value.into_owned()));
+        }
+    }
+
+    if fields.is_empty() {
+        return Err("No metrics given".to_owned())
+    }
+
+    fields.into_iter()
+        .map(|(kind, name, value)| {
+            let mut dim = Dimension::with_name(&name);
+            dim.source = source.clone();
+            dim.tags = tags.clone();
+
+            let number = value.parse::<f64>().ok().filter(|number| number.is_finite());
+            let metric = match kind {
+                "count"   => value.parse().ok().map(|count| Metric::Count(dim, count)),
+                "measure" => number.map(|number| Metric::Measure(dim, number)),
+                "sample"  => number.map(|number| Metric::Sample(dim, number)),
+                _         => Some(Metric::Set(dim, value.clone())),
+            };
+            metric.ok_or_else(|| format!("Invalid {} value for {:?}: {:?}", kind, name, value))
+        })
+        .collect()
+}
+
+/// Splits eg. `count[jobs.done]` into `("count", "jobs.done")`.
+fn split_form_key(key: &str) -> Option<(&'static str, &str)> {
+    let open = key.find('[')?;
+    if !key.ends_with(']') { return None }
+
+    let kind = match &key[..open] {
+        "count"   => "count",
+        "measure" => "measure",
+        "sample"  => "sample",
+        "set"     => "set",
+        "tag"     => "tag",
+        _ => return None,
+    };
+    Some((kind, &key[open + 1..key.len() - 1]))
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+    use rustc_serialize::json::Json;
+
+    use super::{read_body, BodyError, FormBatchHandler, JsonBatchHandler, LogDrainHandler};
+    use super::super::super::SharedStore;
+    use super::super::super::metrics::{AggregatedMetrics, AggregatedMetricType, Dimension, Metric};
+    use super::super::super::parsers::log_line::{LogLineReader, StandardLogLineReader};
+    use super::super::super::parsers::logplex::LogplexMessage;
+
+    const LOGPLEX_BODY: &str = "\
+        82 <40>1 2012-11-30T06:45:29+00:00 host app web.3 - count#jobs=1 State changed to up\n\
+        77 <40>1 2012-11-30T06:45:30+00:00 host app web.3 - count#jobs=2\n  count#jobs=4\n";
+
+    /// Counts the messages it's passed, tagged with their drain tokens.
+    struct DrainTokenReader;
+
+    impl LogLineReader for DrainTokenReader {
+        fn read(&self, _line: &str) -> Vec<Metric> { vec![] }
+
+        fn read_logplex_message(&self, message: &LogplexMessage) -> Vec<Metric> {
+            vec![Metric::Count(Dimension::with_name("messages").tagged("token", message.drain_token.unwrap_or("")), 1)]
+        }
+    }
+
+    #[test]
+    fn log_drain_handler_reads_logplex_frames() {
+        let store = SharedStore::new();
+        let handler = LogDrainHandler::new(store.clone(), vec![Box::new(StandardLogLineReader), Box::new(DrainTokenReader)]);
+
+        let metrics = handler.read_logplex(LOGPLEX_BODY, Some("2"), Some("d.abc")).unwrap();
+        store.record(metrics);
+
+        let flushed = store.flush();
+        let mut counts: Vec<(&Dimension, f64)> = flushed.iter().map(|&(_, ref dim, value)| (dim, value)).collect();
+        counts.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+        assert_eq!(counts, vec![
+            (&Dimension::with_name("jobs"), 7.0),
+            (&Dimension::with_name("messages").tagged("token", "d.abc"), 2.0),
+        ]);
+    }
+
+    #[test]
+    fn log_drain_handler_rejects_mismatched_message_counts() {
+        let handler = LogDrainHandler::new(SharedStore::new(), vec![Box::new(StandardLogLineReader)]);
+
+        assert_eq!(
+            handler.read_logplex(LOGPLEX_BODY, Some("3"), None),
+            Err("Logplex-Msg-Count is \"3\" but the body has 2 messages".to_owned())
+        );
+        assert!(handler.read_logplex("83 <40>1 truncated", None, None).is_err());
+    }
+
+    #[test]
+    fn read_body_rejects_large_and_non_utf8_bodies() {
+        assert_eq!(read_body(&mut &b"cpu value=1"[..], 11).unwrap(), "cpu value=1");
+        assert!(matches!(read_body(&mut &b"cpu value=1"[..], 10), Err(BodyError::TooLarge)));
+        assert!(matches!(read_body(&mut &b"cpu value=\xff"[..], 100), Err(BodyError::NotUtf8)));
+    }
+
+    #[test]
+    fn json_batch_handler_records_metrics() {
+        let store = SharedStore::new();
+        let handler = JsonBatchHandler::new(store.clone());
+
+        let (status, json) = handler.record(r#"[
+            {"type": "count", "name": "jobs", "source": "worker.1", "tags": {"queue": "mail"}, "value": 3},
+            {"type": "sample", "name": "load", "value": 0.5}
+        ]"#);
+
+        assert_eq!(status, Status::Created);
+        assert_eq!(json.to_string(), r#"{"errors":[],"recorded":2}"#);
+
+        assert_eq!(store.flush(), AggregatedMetrics::with_metrics(vec![
+            (AggregatedMetricType::Count, Dimension::with_name_and_source("jobs", "worker.1").tagged("queue", "mail"), 3.0),
+            (AggregatedMetricType::Sample, Dimension::with_name("load"), 0.5),
+        ]));
+    }
+
+    #[test]
+    fn json_batch_handler_reports_invalid_items() {
+        let store = SharedStore::new();
+        let handler = JsonBatchHandler::new(store.clone());
+
+        let (status, json) = handler.record(r#"[
+            {"type": "count", "name": "jobs", "value": 1.5},
+            {"type": "set", "name": "users", "value": "bob"},
+            {"type": "gauge", "name": "load", "value": 1},
+            {"type": "sample", "value": 1},
+            {"type": "sample", "name": "load", "tags": {"host": 1}, "value": 1}
+        ]"#);
+
+        assert_eq!(status, Status::BadRequest);
+        assert_eq!(json.find("recorded"), Some(&Json::U64(1)));
+        let errors: Vec<(u64, &str)> = json.find("errors").unwrap().as_array().unwrap().iter()
+            .map(|error| (error.find("index").unwrap().as_u64().unwrap(), error.find("error").unwrap().as_string().unwrap()))
+            .collect();
+        assert_eq!(errors, vec![
+            (0, "Count value must be a whole number"),
+            (2, "Unknown type \"gauge\""),
+            (3, "Missing name"),
+            (4, "Tag \"host\" must be a string"),
+        ]);
+        assert_eq!(store.flush().len(), 1);
+    }
+
+    #[test]
+    fn json_batch_handler_rejects_non_arrays() {
+        let handler = JsonBatchHandler::new(SharedStore::new());
+
+        assert_eq!(handler.record("{}").0, Status::BadRequest);
+        assert_eq!(handler.record("[").0, Status::BadRequest);
+    }
+
+    #[test]
+    fn form_batch_handler_records_metrics() {
+        let store = SharedStore::new();
+        let handler = FormBatchHandler::new(store.clone());
+
+        handler.record("count[jobs]=3&count[jobs]=2&sample[load]=0.5&source=host1&tag[env]=prod\n").unwrap();
+
+        let dim = |name| Dimension::with_name_and_source(name, "host1").tagged("env", "prod");
+        assert_eq!(store.flush(), AggregatedMetrics::with_metrics(vec![
+            (AggregatedMetricType::Count, dim("jobs"), 5.0),
+            (AggregatedMetricType::Sample, dim("load"), 0.5),
+        ]));
+    }
+
+    #[test]
+    fn form_batch_handler_rejects_invalid_bodies() {
+        let store = SharedStore::new();
+        let handler = FormBatchHandler::new(store.clone());
+
+        assert_eq!(handler.record("count[jobs]=1&measure[time]=fast"), Err("Invalid measure value for \"time\": \"fast\"".to_owned()));
+        assert_eq!(handler.record("count[jobs]=1.5"), Err("Invalid count value for \"jobs\": \"1.5\"".to_owned()));
+        assert_eq!(handler.record("gauge[load]=1"), Err("Unknown field \"gauge[load]\"".to_owned()));
+        assert_eq!(handler.record("count[]=1"), Err("Missing name in \"count[]\"".to_owned()));
+        assert_eq!(handler.record("source=host1"), Err("No metrics given".to_owned()));
+        assert_eq!(store.flush().len(), 0);
+    }
+}
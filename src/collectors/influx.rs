@@ -0,0 +1,188 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use iron::headers::ContentType;
+use iron::middleware::Handler;
+use iron::prelude::*;
+use iron::status::Status;
+use rustc_serialize::json::{Json, ToJson};
+
+use super::http::{read_body, BodyError};
+use super::listener::{protocol_listener, Batch, UdpDatagramListener};
+use super::super::SharedStore;
+use super::super::parsers::influx::{parse_points, Precision};
+
+/// Mimics InfluxDB's `/write` endpoint so that Telegraf and other Influx
+/// agents can write to the distributor. Mount it at `/write` with a POST
+/// route; the `db` and `rp` parameters are ignored.
+///
+/// Like InfluxDB, valid points are recorded even when some lines are
+/// malformed, and the response is then a `400` describing the errors.
+/// Otherwise it's a `204`.
+pub struct InfluxWriteHandler {
+    /// Bodies larger than this many bytes are rejected with a `413` without
+    /// recording any of their points. Defaults to 25MB like InfluxDB.
+    pub max_body_size: usize,
+    store: SharedStore,
+    parse_errors: AtomicUsize,
+}
+
+impl InfluxWriteHandler {
+    pub fn new(store: SharedStore) -> InfluxWriteHandler {
+        InfluxWriteHandler {
+            max_body_size: 25_000_000,
+            store,
+            parse_errors: AtomicUsize::new(0),
+        }
+    }
+
+    /// Number of malformed lines received so far.
+    pub fn parse_error_count(&self) -> usize {
+        self.parse_errors.load(Ordering::Relaxed)
+    }
+
+    /// Records the points in a request's body, using the `precision` from
+    /// its query string.
+    fn write(&self, body: &str, query: Option<&str>) -> Result<(), String> {
+        let precision = match query_param(query, "precision") {
+            Some(precision) => precision.parse()?,
+            None => Precision::default(),
+        };
+
+        let parsed = parse_points(body, precision);
+        let metrics = parsed.points.iter()
+            .flat_map(|point| point.to_standard_metrics(precision))
+            .collect();
+        self.store.record(metrics);
+
+        if parsed.errors.is_empty() {
+            return Ok(())
+        }
+
+        self.parse_errors.fetch_add(parsed.errors.len(), Ordering::Relaxed);
+        let errors: Vec<String> = parsed.errors.iter().map(|err| err.to_string()).collect();
+        Err(format!("partial write: {}", errors.join("; ")))
+    }
+}
+
+impl Handler for InfluxWriteHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let body = match read_body(&mut req.body, self.max_body_size) {
+            Ok(body) => body,
+            Err(BodyError::Io(error)) => {
+                println!("{:?}", error);
+                return Err(IronError::new(error, Status::InternalServerError))
+            },
+            Err(BodyError::TooLarge) => {
+                let error = format!("request body is larger than {} bytes", self.max_body_size);
+                return Ok(error_response(Status::PayloadTooLarge, error))
+            },
+            Err(BodyError::NotUtf8) => {
+                return Ok(error_response(Status::BadRequest, "request body is not valid UTF-8".to_owned()))
+            },
+        };
+
+        match self.write(&body, req.url.query()) {
+            Ok(()) => Ok(Response::with(Status::NoContent)),
+            Err(error) => Ok(error_response(Status::BadRequest, error)),
+        }
+    }
+}
+
+/// Response with an InfluxDB-style `{"error": "..."}` body.
+fn error_response(status: Status, error: String) -> Response {
+    let mut object = BTreeMap::new();
+    object.insert("error".to_owned(), error.to_json());
+
+    let mut response = Response::with((status, Json::Object(object).to_string()));
+    response.headers.set(ContentType::json());
+    response
+}
+
+/// Finds a parameter in a query string. Only used for simple values like
+/// `precision` so it doesn't percent-decode.
+fn query_param<'a>(query: Option<&'a str>, name: &str) -> Option<&'a str> {
+    query?.split('&').find_map(|pair| {
+        let mut pair = pair.splitn(2, '=');
+        match (pair.next(), pair.next()) {
+            (Some(key), Some(value)) if key == name => Some(value),
+            _ => None,
+        }
+    })
+}
+
+protocol_listener! {
+    /// Listens for line protocol UDP datagrams, like InfluxDB's UDP service.
+    /// Timestamps are expected to be in nanoseconds. See
+    /// `UdpDatagramListener` for its settings.
+    pub struct InfluxUdpListener(UdpDatagramListener, parse_message);
+}
+
+/// Parses a datagram of line protocol into the batch, returning the number
+/// of malformed lines in it (which are counted rather than logged).
+fn parse_message(batch: &mut Batch, message: &str) -> usize {
+    let parsed = parse_points(message, Precision::Nanoseconds);
+
+    for point in parsed.points.iter() {
+        batch.metrics.extend(point.to_standard_metrics(Precision::Nanoseconds))
+    }
+
+    parsed.errors.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{query_param, InfluxUdpListener, InfluxWriteHandler};
+    use super::super::super::SharedStore;
+    use super::super::super::metrics::{AggregatedMetrics, AggregatedMetricType, Dimension};
+
+    #[test]
+    fn write_records_valid_points_despite_errors() {
+        let store = SharedStore::new();
+        let handler = InfluxWriteHandler::new(store.clone());
+
+        let result = handler.write("cpu,host=a value=1 1500000000\nmem oops\n", Some("db=telegraf&precision=s"));
+
+        assert!(result.unwrap_err().starts_with("partial write"));
+        assert_eq!(handler.parse_error_count(), 1);
+        assert_eq!(store.flush(), AggregatedMetrics::with_metrics(vec![
+            (AggregatedMetricType::Sample, Dimension::with_name("cpu").tagged("host", "a"), 1.0),
+        ]));
+    }
+
+    #[test]
+    fn write_rejects_invalid_precision() {
+        let handler = InfluxWriteHandler::new(SharedStore::new());
+
+        assert!(handler.write("cpu value=1", None).is_ok());
+        assert!(handler.write("cpu value=1", Some("precision=weeks")).is_err());
+    }
+
+    #[test]
+    fn it_finds_query_params() {
+        assert_eq!(query_param(Some("db=a&precision=ms"), "precision"), Some("ms"));
+        assert_eq!(query_param(Some("db=a"), "precision"), None);
+        assert_eq!(query_param(None, "precision"), None);
+    }
+
+    #[test]
+    fn udp_listener_records_datagrams() {
+        let store = SharedStore::new();
+        let listener = InfluxUdpListener::new(store.clone());
+
+        let handle = listener.spawn("127.0.0.1:0").unwrap();
+        let addr = handle.local_addr().unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"cpu usage=1,idle=2 1500000000000000000\nmem", addr).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        handle.shutdown().unwrap();
+
+        assert_eq!(listener.parse_error_count(), 1);
+        assert_eq!(store.flush().len(), 2);
+    }
+}
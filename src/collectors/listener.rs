@@ -0,0 +1,714 @@
+use socket2::{Domain, Socket, Type};
+use std::error;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read};
+use std::mem;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::str;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+use super::queue::{BoundedQueue, OverflowPolicy};
+use super::super::SharedStore;
+use super::super::metrics::{Event, Metric, ServiceCheck};
+
+/// How often listener threads blocked on a socket wake up to check whether
+/// they've been asked to stop.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Error starting or running a listener.
+#[derive(Debug)]
+pub enum ListenerError {
+    /// The listener's socket couldn't be bound to the given address.
+    Bind(io::Error),
+    /// The listener's socket couldn't be set up after binding.
+    Io(io::Error),
+    /// One of the listener's settings is invalid, so it wasn't started.
+    InvalidOption(&'static str),
+    /// One of the listener's threads panicked.
+    Panicked,
+}
+
+impl fmt::Display for ListenerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ListenerError::Bind(ref err)          => write!(f, "Failed to bind listener: {}", err),
+            ListenerError::Io(ref err)            => write!(f, "Failed to set up listener: {}", err),
+            ListenerError::InvalidOption(message) => write!(f, "Invalid listener option: {}", message),
+            ListenerError::Panicked               => write!(f, "Listener thread panicked"),
+        }
+    }
+}
+
+impl error::Error for ListenerError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            ListenerError::Bind(ref err)    => Some(err),
+            ListenerError::Io(ref err)      => Some(err),
+            ListenerError::InvalidOption(_) => None,
+            ListenerError::Panicked         => None,
+        }
+    }
+}
+
+/// Handle to a running listener's threads. Use it to stop the listener and
+/// wait for everything it received to be recorded.
+pub struct ListenerHandle {
+    shutdown: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+    local_addr: Option<SocketAddr>,
+}
+
+impl ListenerHandle {
+    pub(crate) fn new(shutdown: Arc<AtomicBool>, threads: Vec<JoinHandle<()>>, local_addr: Option<SocketAddr>) -> ListenerHandle {
+        ListenerHandle {
+            shutdown,
+            threads,
+            local_addr,
+        }
+    }
+
+    /// Address the listener is bound to (if it's listening on a network
+    /// socket). Useful when binding to port 0.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// Asks the listener's threads to stop receiving. They will notice
+    /// within `POLL_INTERVAL`.
+    pub fn stop(&self) {
+        self.shutdown.store(true, Ordering::SeqCst)
+    }
+
+    /// Waits for all of the listener's threads to finish. Unless `stop` has
+    /// been called this will block forever.
+    pub fn join(self) -> Result<(), ListenerError> {
+        let mut result = Ok(());
+
+        for thread in self.threads {
+            if thread.join().is_err() {
+                result = Err(ListenerError::Panicked)
+            }
+        }
+
+        result
+    }
+
+    /// Stops the listener and waits until all the messages it has already
+    /// received have been recorded.
+    pub fn shutdown(self) -> Result<(), ListenerError> {
+        self.stop();
+        self.join()
+    }
+}
+
+/// Whether an IO error is just a read timing out so that we can check for
+/// shutdown.
+pub(crate) fn is_timeout(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut
+}
+
+/// Largest payload a UDP datagram can carry.
+pub const MAX_DATAGRAM_SIZE: usize = 65507;
+
+/// Parses a message in a listener's protocol into a batch, returning the
+/// number of malformed metrics in it.
+pub(crate) type Parse = fn(&mut Batch, &str) -> usize;
+
+/// Parsed metrics, events, and service checks waiting to be recorded
+/// together, so that busy listeners take the store's lock once per batch
+/// rather than once per message.
+pub(crate) struct Batch {
+    pub metrics: Vec<Metric>,
+    pub events: Vec<Event>,
+    pub service_checks: Vec<ServiceCheck>,
+}
+
+impl Batch {
+    pub fn new() -> Batch {
+        Batch {
+            metrics: vec![],
+            events: vec![],
+            service_checks: vec![],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.metrics.len() + self.events.len() + self.service_checks.len()
+    }
+
+    /// Records everything in the batch, leaving it empty.
+    pub fn record(&mut self, store: &SharedStore) {
+        if !self.metrics.is_empty() {
+            store.record(mem::take(&mut self.metrics));
+        }
+        if !self.events.is_empty() {
+            store.record_events(mem::take(&mut self.events));
+        }
+        if !self.service_checks.is_empty() {
+            store.record_service_checks(mem::take(&mut self.service_checks));
+        }
+    }
+}
+
+/// Parses a single message and records it straight away, returning the
+/// number of malformed metrics in it.
+pub(crate) fn handle_message(store: &SharedStore, parse: Parse, message: &str) -> usize {
+    let mut batch = Batch::new();
+    let errors = parse(&mut batch, message);
+    batch.record(store);
+    errors
+}
+
+/// Defines a protocol's listener as a wrapper around `TcpLineListener` or
+/// `UdpDatagramListener` that parses what it receives with the given
+/// function. The wrapper derefs to the generic listener for its settings,
+/// counters, and `listen`/`spawn`.
+macro_rules! protocol_listener {
+    ($(#[$attr:meta])* pub struct $name:ident($listener:ident, $parse:expr);) => {
+        $(#[$attr])*
+        pub struct $name($listener);
+
+        impl $name {
+            pub fn new(store: $crate::SharedStore) -> $name {
+                $name($listener::new(store, $parse))
+            }
+        }
+
+        impl ::std::ops::Deref for $name {
+            type Target = $listener;
+
+            fn deref(&self) -> &$listener {
+                &self.0
+            }
+        }
+
+        impl ::std::ops::DerefMut for $name {
+            fn deref_mut(&mut self) -> &mut $listener {
+                &mut self.0
+            }
+        }
+    }
+}
+pub(crate) use protocol_listener;
+
+/// Listens on a TCP socket for newline-separated messages. This holds the
+/// settings of the protocol-specific TCP listeners (`StatsdTcpListener`,
+/// `GraphiteTcpListener`), which only differ in how they parse lines.
+pub struct TcpLineListener {
+    store: SharedStore,
+    parse: Parse,
+    parse_errors: Arc<AtomicUsize>,
+    dropped_lines: Arc<AtomicUsize>,
+    rejected_connections: Arc<AtomicUsize>,
+    /// Most clients that can be connected at once; connections beyond that
+    /// are closed straight away. Unlimited if not set.
+    pub max_connections: Option<usize>,
+    /// Most lines that can be waiting to be recorded. Must be at least 1.
+    pub queue_capacity: usize,
+    /// What to do with lines received when the queue is full.
+    pub overflow_policy: OverflowPolicy,
+    /// How long clients can go without sending us anything before we'll
+    /// close their connection.
+    pub idle_timeout: Duration,
+}
+
+impl TcpLineListener {
+    pub(crate) fn new(store: SharedStore, parse: Parse) -> TcpLineListener {
+        TcpLineListener {
+            store,
+            parse,
+            parse_errors: Arc::new(AtomicUsize::new(0)),
+            dropped_lines: Arc::new(AtomicUsize::new(0)),
+            rejected_connections: Arc::new(AtomicUsize::new(0)),
+            max_connections: Some(1024),
+            queue_capacity: 10_000,
+            overflow_policy: OverflowPolicy::Block,
+            idle_timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Number of malformed metrics received so far.
+    pub fn parse_error_count(&self) -> usize {
+        self.parse_errors.load(Ordering::Relaxed)
+    }
+
+    /// Number of lines dropped so far because the queue was full.
+    pub fn dropped_line_count(&self) -> usize {
+        self.dropped_lines.load(Ordering::Relaxed)
+    }
+
+    /// Number of connections closed so far because there were already
+    /// `max_connections` clients connected.
+    pub fn rejected_connection_count(&self) -> usize {
+        self.rejected_connections.load(Ordering::Relaxed)
+    }
+
+    /// Listens for TCP connections, blocking the calling thread until the
+    /// listener fails. Use `spawn` to be able to stop the listener.
+    pub fn listen<A>(&self, addr: A) -> Result<(), ListenerError>
+        where A: ToSocketAddrs {
+        self.spawn(addr)?.join()
+    }
+
+    /// Spawns a separate thread to listen for TCP connections; connections
+    /// opened then spawn another thread that reads lines from the client.
+    /// Those lines are queued for a recording thread which parses them and
+    /// records their metrics in the store.
+    pub fn spawn<A>(&self, addr: A) -> Result<ListenerHandle, ListenerError>
+        where A: ToSocketAddrs {
+        check_queue_capacity(self.queue_capacity)?;
+        let listener = TcpListener::bind(addr).map_err(ListenerError::Bind)?;
+        let local_addr = listener.local_addr().map_err(ListenerError::Io)?;
+        // Don't block in `accept` so that we can check for shutdown.
+        listener.set_nonblocking(true).map_err(ListenerError::Io)?;
+
+        let accept = move || listener.accept().map(|(stream, _)| stream);
+        Ok(spawn_stream_listener(accept, self.stream_options(), Some(local_addr)))
+    }
+
+    /// Settings and counters for the listener's threads, which are shared
+    /// with other kinds of stream socket (eg. Unix sockets).
+    pub(crate) fn stream_options(&self) -> StreamOptions {
+        StreamOptions {
+            store: self.store.clone(),
+            parse: self.parse,
+            parse_errors: self.parse_errors.clone(),
+            dropped_lines: self.dropped_lines.clone(),
+            rejected_connections: self.rejected_connections.clone(),
+            max_connections: self.max_connections,
+            queue_capacity: self.queue_capacity,
+            overflow_policy: self.overflow_policy,
+            idle_timeout: self.idle_timeout,
+        }
+    }
+} // impl TcpLineListener
+
+/// Listens for UDP datagrams holding one or more lines. Like
+/// `TcpLineListener` this holds the settings of the protocol-specific UDP
+/// listeners (`StatsdUdpListener`, `GraphiteUdpListener`, and
+/// `InfluxUdpListener`).
+pub struct UdpDatagramListener {
+    store: SharedStore,
+    parse: Parse,
+    parse_errors: Arc<AtomicUsize>,
+    invalid_datagrams: Arc<AtomicUsize>,
+    /// Largest datagram (in bytes) that will be accepted; larger datagrams
+    /// are dropped rather than parsed truncated. Can't be more than
+    /// `MAX_DATAGRAM_SIZE`.
+    pub max_datagram_size: usize,
+    /// Size to request for the socket's receive buffer (`SO_RCVBUF`). A
+    /// bigger buffer means fewer datagrams dropped during bursts. Uses the
+    /// operating system's default if not set.
+    pub recv_buffer_size: Option<usize>,
+    /// Number of sockets (each with its own thread) to receive and parse
+    /// datagrams on. More than one binds every socket with `SO_REUSEPORT`
+    /// so that the kernel spreads datagrams across them; that's only
+    /// supported on Unix.
+    pub receive_threads: usize,
+    /// Most metrics each receiving thread will parse before recording them
+    /// in the store all at once. Metrics are never held back for more than
+    /// `POLL_INTERVAL` though.
+    pub batch_size: usize,
+}
+
+impl UdpDatagramListener {
+    pub(crate) fn new(store: SharedStore, parse: Parse) -> UdpDatagramListener {
+        UdpDatagramListener {
+            store,
+            parse,
+            parse_errors: Arc::new(AtomicUsize::new(0)),
+            invalid_datagrams: Arc::new(AtomicUsize::new(0)),
+            max_datagram_size: 8192,
+            recv_buffer_size: None,
+            receive_threads: 1,
+            batch_size: 1000,
+        }
+    }
+
+    /// Number of malformed metrics received so far.
+    pub fn parse_error_count(&self) -> usize {
+        self.parse_errors.load(Ordering::Relaxed)
+    }
+
+    /// Number of datagrams dropped so far because they were too big or
+    /// weren't valid UTF-8.
+    pub fn invalid_datagram_count(&self) -> usize {
+        self.invalid_datagrams.load(Ordering::Relaxed)
+    }
+
+    /// Listens for datagrams, blocking the calling thread until the listener
+    /// fails. Use `spawn` to be able to stop the listener.
+    pub fn listen<A>(&self, addr: A) -> Result<(), ListenerError>
+        where A: ToSocketAddrs {
+        self.spawn(addr)?.join()
+    }
+
+    /// Spawns `receive_threads` threads that each receive datagrams on their
+    /// own socket, parse them, and record their metrics in the store in
+    /// batches.
+    pub fn spawn<A>(&self, addr: A) -> Result<ListenerHandle, ListenerError>
+        where A: ToSocketAddrs {
+        let sockets = bind_udp_sockets(addr, self.receive_threads, self.recv_buffer_size)?;
+        let local_addr = sockets[0].local_addr().map_err(ListenerError::Io)?;
+
+        let recvs = sockets.into_iter().map(|socket| {
+            move |buf: &mut [u8]| socket.recv_from(buf).map(|(bytes_read, _)| bytes_read)
+        }).collect();
+        Ok(spawn_datagram_listener(recvs, self.datagram_options(), Some(local_addr)))
+    }
+
+    /// Settings and counters for the receiving threads, which are shared
+    /// with other kinds of datagram socket (eg. Unix sockets).
+    pub(crate) fn datagram_options(&self) -> DatagramOptions {
+        DatagramOptions {
+            store: self.store.clone(),
+            parse: self.parse,
+            parse_errors: self.parse_errors.clone(),
+            invalid_datagrams: self.invalid_datagrams.clone(),
+            max_datagram_size: self.max_datagram_size,
+            batch_size: self.batch_size,
+        }
+    }
+} // impl UdpDatagramListener
+
+/// Everything the threads of a stream (TCP or Unix stream socket) listener
+/// need to know.
+#[derive(Clone)]
+pub(crate) struct StreamOptions {
+    pub store: SharedStore,
+    pub parse: Parse,
+    pub parse_errors: Arc<AtomicUsize>,
+    pub dropped_lines: Arc<AtomicUsize>,
+    pub rejected_connections: Arc<AtomicUsize>,
+    pub max_connections: Option<usize>,
+    pub queue_capacity: usize,
+    pub overflow_policy: OverflowPolicy,
+    pub idle_timeout: Duration,
+}
+
+/// Checks a stream listener's queue capacity before its socket is bound,
+/// since a queue has to be able to hold at least one line.
+pub(crate) fn check_queue_capacity(queue_capacity: usize) -> Result<(), ListenerError> {
+    if queue_capacity == 0 {
+        return Err(ListenerError::InvalidOption("queue_capacity must be at least 1"))
+    }
+    Ok(())
+}
+
+/// Stream sockets that clients send us lines over.
+pub(crate) trait ClientStream: Read + Send + 'static {
+    /// Makes reads block for no longer than `POLL_INTERVAL`.
+    fn set_poll_timeout(&self) -> io::Result<()>;
+}
+
+impl ClientStream for TcpStream {
+    fn set_poll_timeout(&self) -> io::Result<()> {
+        self.set_nonblocking(false)?;
+        self.set_read_timeout(Some(POLL_INTERVAL))
+    }
+}
+
+#[cfg(unix)]
+impl ClientStream for UnixStream {
+    fn set_poll_timeout(&self) -> io::Result<()> {
+        self.set_nonblocking(false)?;
+        self.set_read_timeout(Some(POLL_INTERVAL))
+    }
+}
+
+/// Spawns a thread that accepts clients with a non-blocking `accept` and
+/// gives each one its own thread to read lines on. Lines are queued for a
+/// recording thread which parses them and records their metrics.
+pub(crate) fn spawn_stream_listener<S, A>(accept: A, options: StreamOptions, local_addr: Option<SocketAddr>) -> ListenerHandle
+    where S: ClientStream, A: FnMut() -> io::Result<S> + Send + 'static {
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let queue = Arc::new(BoundedQueue::new(options.queue_capacity, options.overflow_policy));
+
+    let client_options = ClientOptions {
+        queue: queue.clone(),
+        shutdown: shutdown.clone(),
+        idle_timeout: options.idle_timeout,
+        dropped_lines: options.dropped_lines.clone(),
+    };
+    let max_connections = options.max_connections;
+    let rejected_connections = options.rejected_connections.clone();
+
+    let accepting = thread::spawn(move || {
+        accept_clients(accept, client_options, max_connections, rejected_connections)
+    });
+
+    let recording = thread::spawn(move || {
+        for line in BoundedQueue::consume(queue) {
+            let errors = handle_message(&options.store, options.parse, &line);
+            options.parse_errors.fetch_add(errors, Ordering::Relaxed);
+        }
+    });
+
+    ListenerHandle::new(shutdown, vec![accepting, recording], local_addr)
+}
+
+/// Everything a client's thread needs to know.
+#[derive(Clone)]
+struct ClientOptions {
+    queue: Arc<BoundedQueue<String>>,
+    shutdown: Arc<AtomicBool>,
+    idle_timeout: Duration,
+    dropped_lines: Arc<AtomicUsize>,
+}
+
+impl ClientOptions {
+    fn send(&self, line: String) {
+        if !self.queue.push(line) {
+            self.dropped_lines.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Counts an open connection for as long as it's alive.
+struct Connection(Arc<AtomicUsize>);
+
+impl Connection {
+    fn open(connections: &Arc<AtomicUsize>) -> Connection {
+        connections.fetch_add(1, Ordering::SeqCst);
+        Connection(connections.clone())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn accept_clients<S, A>(mut accept: A, options: ClientOptions, max_connections: Option<usize>, rejected_connections: Arc<AtomicUsize>)
+    where S: ClientStream, A: FnMut() -> io::Result<S> {
+    let connections = Arc::new(AtomicUsize::new(0));
+
+    while !options.shutdown.load(Ordering::SeqCst) {
+        match accept() {
+            Ok(stream) => {
+                if max_connections.map_or(false, |max| connections.load(Ordering::SeqCst) >= max) {
+                    rejected_connections.fetch_add(1, Ordering::Relaxed);
+                    continue
+                }
+
+                let options = options.clone();
+                let connection = Connection::open(&connections);
+
+                thread::spawn(move || {
+                    handle_client(stream, options);
+                    drop(connection)
+                });
+            },
+            Err(ref err) if is_timeout(err) => {
+                thread::sleep(POLL_INTERVAL)
+            },
+            Err(err) => {
+                // Most likely out of file descriptors or the client went
+                // away before we got to it; back off and keep going.
+                println!("Error accepting connection: {:?}", err);
+                thread::sleep(POLL_INTERVAL)
+            },
+        }
+    }
+
+    // Let clients finish sending what they've read before telling the
+    // recording thread that nothing more is coming.
+    while connections.load(Ordering::SeqCst) > 0 {
+        thread::sleep(POLL_INTERVAL)
+    }
+    options.queue.close()
+}
+
+fn handle_client<S: ClientStream>(stream: S, options: ClientOptions) {
+    if let Err(err) = stream.set_poll_timeout() {
+        println!("Error setting up connection: {:?}", err);
+        return
+    }
+
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    let mut idle = Duration::from_secs(0);
+
+    loop {
+        // A read that times out keeps whatever it had read of the line so
+        // far, so we carry on appending to it.
+        match reader.read_line(&mut line) {
+            Err(ref err) if is_timeout(err) => {
+                idle += POLL_INTERVAL;
+                if idle >= options.idle_timeout || options.shutdown.load(Ordering::SeqCst) {
+                    break
+                }
+            },
+            Err(err) => {
+                println!("Error reading line: {:?}", err);
+                break
+            },
+            Ok(0) => {
+                // Close if there are no more bytes.
+                break
+            },
+            Ok(_) => {
+                idle = Duration::from_secs(0);
+                options.send(line);
+                line = String::new();
+                // A client that never stops sending would otherwise keep
+                // its connection, and the listener, open forever.
+                if options.shutdown.load(Ordering::SeqCst) {
+                    break
+                }
+            },
+        }
+    }
+
+    // Don't lose a final line that was never terminated.
+    if !line.is_empty() {
+        options.send(line);
+    }
+} // fn handle_client
+
+/// Everything the threads receiving datagrams need to know.
+#[derive(Clone)]
+pub(crate) struct DatagramOptions {
+    pub store: SharedStore,
+    pub parse: Parse,
+    pub parse_errors: Arc<AtomicUsize>,
+    pub invalid_datagrams: Arc<AtomicUsize>,
+    pub max_datagram_size: usize,
+    pub batch_size: usize,
+}
+
+/// Spawns a thread for each `recv` (which should time out after
+/// `POLL_INTERVAL`) that receives datagrams until shutdown, parses them,
+/// and records their metrics in batches.
+pub(crate) fn spawn_datagram_listener<R>(recvs: Vec<R>, options: DatagramOptions, local_addr: Option<SocketAddr>) -> ListenerHandle
+    where R: FnMut(&mut [u8]) -> io::Result<usize> + Send + 'static {
+    let shutdown = Arc::new(AtomicBool::new(false));
+
+    let threads = recvs.into_iter().map(|recv| {
+        let options = options.clone();
+        let shutdown = shutdown.clone();
+        thread::spawn(move || receive_datagrams(recv, options, shutdown))
+    }).collect();
+
+    ListenerHandle::new(shutdown, threads, local_addr)
+}
+
+fn receive_datagrams<R>(mut recv: R, options: DatagramOptions, shutdown: Arc<AtomicBool>)
+    where R: FnMut(&mut [u8]) -> io::Result<usize> {
+    let max_datagram_size = options.max_datagram_size.min(MAX_DATAGRAM_SIZE);
+    // One extra byte so that we can tell when a datagram didn't fit.
+    let mut buf = vec![0; max_datagram_size + 1];
+    let mut batch = Batch::new();
+    let mut last_recorded = Instant::now();
+
+    while !shutdown.load(Ordering::SeqCst) {
+        let bytes_read = match recv(&mut buf) {
+            Ok(bytes_read) => bytes_read,
+            Err(ref err) if is_timeout(err) => {
+                batch.record(&options.store);
+                last_recorded = Instant::now();
+                continue
+            },
+            Err(err) => {
+                println!("Error receiving datagram: {:?}", err);
+                continue
+            },
+        };
+
+        match decode_datagram(&buf, bytes_read, max_datagram_size) {
+            Some(message) => {
+                let errors = (options.parse)(&mut batch, message);
+                options.parse_errors.fetch_add(errors, Ordering::Relaxed);
+            },
+            None => {
+                options.invalid_datagrams.fetch_add(1, Ordering::Relaxed);
+            },
+        }
+
+        if batch.len() >= options.batch_size || last_recorded.elapsed() >= POLL_INTERVAL {
+            batch.record(&options.store);
+            last_recorded = Instant::now();
+        }
+    }
+
+    batch.record(&options.store)
+}
+
+/// Gets the message out of a received datagram; `None` if the datagram was
+/// too big or isn't valid UTF-8. Like malformed metrics those are counted
+/// by the caller rather than logged.
+pub(crate) fn decode_datagram(buf: &[u8], bytes_read: usize, max_datagram_size: usize) -> Option<&str> {
+    if bytes_read > max_datagram_size {
+        return None
+    }
+
+    // Get a string from just the amount of bytes read.
+    str::from_utf8(&buf[..bytes_read]).ok()
+}
+
+/// Binds `count` UDP sockets to the address, setting the size of their
+/// receive buffers if one is given. Binding more than one socket uses
+/// `SO_REUSEPORT` so that the kernel spreads datagrams across them.
+pub(crate) fn bind_udp_sockets<A>(addr: A, count: usize, recv_buffer_size: Option<usize>) -> Result<Vec<UdpSocket>, ListenerError>
+    where A: ToSocketAddrs {
+    let reuse_port = count > 1;
+    let first = bind_udp(addr, recv_buffer_size, reuse_port).map_err(ListenerError::Bind)?;
+    // Bind the rest to the address we actually got in case the port was
+    // picked by the operating system.
+    let local_addr = first.local_addr().map_err(ListenerError::Io)?;
+
+    let mut sockets = vec![first];
+    for _ in 1..count {
+        sockets.push(bind_udp(local_addr, recv_buffer_size, reuse_port).map_err(ListenerError::Bind)?);
+    }
+
+    for socket in sockets.iter() {
+        socket.set_read_timeout(Some(POLL_INTERVAL)).map_err(ListenerError::Io)?;
+    }
+
+    Ok(sockets)
+}
+
+/// Binds a UDP socket, setting the size of its receive buffer first if one
+/// is given. With `reuse_port` other sockets can be bound to the same
+/// address too.
+pub(crate) fn bind_udp<A>(addr: A, recv_buffer_size: Option<usize>, reuse_port: bool) -> io::Result<UdpSocket>
+    where A: ToSocketAddrs {
+    let addr = first_addr(addr)?;
+
+    let domain = if addr.is_ipv4() { Domain::ipv4() } else { Domain::ipv6() };
+    let socket = Socket::new(domain, Type::dgram(), None)?;
+
+    if let Some(size) = recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if reuse_port {
+        set_reuse_port(&socket)?;
+    }
+
+    socket.bind(&addr.into())?;
+    Ok(socket.into_udp_socket())
+}
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn set_reuse_port(_socket: &Socket) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "SO_REUSEPORT isn't supported on this platform"))
+}
+
+pub(crate) fn first_addr<A: ToSocketAddrs>(addr: A) -> io::Result<SocketAddr> {
+    addr.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No address to bind to"))
+}
@@ -1,8 +1,24 @@
 //! Collectors listen for metrics in various protocols. They record metrics
 //! they receive in a `SharedStore`.
 
+/// Provides UDP and TCP Graphite plaintext servers.
+pub mod graphite;
+
 /// Tools for building collectors to be exposed through the Iron HTTP library.
 pub mod http;
 
+/// InfluxDB line protocol `/write` handler and UDP server.
+pub mod influx;
+
+/// Handles and errors shared by the socket listeners.
+pub mod listener;
+
+/// Bounded queue of messages waiting to be recorded.
+pub mod queue;
+
 /// Provides UDP and TCP StatsD servers.
 pub mod statsd;
+
+/// Async (tokio) versions of the StatsD servers.
+#[cfg(feature = "tokio")]
+pub mod async_statsd;
@@ -0,0 +1,171 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+
+/// What to do with a message when a listener's queue is full.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OverflowPolicy {
+    /// Wait for the queue to have room. This applies backpressure to
+    /// clients (TCP clients will see their writes slow down).
+    Block,
+    /// Drop the message that didn't fit.
+    DropNewest,
+    /// Drop the oldest message in the queue to make room.
+    DropOldest,
+}
+
+/// Queue of messages waiting to be recorded that holds at most `capacity`
+/// messages. Producers push onto it and a single consumer pops off of it
+/// until it's closed and empty.
+pub struct BoundedQueue<T> {
+    state: Mutex<State<T>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    capacity: usize,
+    policy: OverflowPolicy,
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    closed: bool,
+}
+
+impl<T> BoundedQueue<T> {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> BoundedQueue<T> {
+        assert!(capacity > 0, "Queue capacity must be at least 1");
+
+        BoundedQueue {
+            state: Mutex::new(State {
+                items: VecDeque::with_capacity(capacity),
+                closed: false,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            capacity,
+            policy,
+        }
+    }
+
+    /// Adds an item to the queue, applying the overflow policy if it's full.
+    /// Returns false if an item had to be dropped.
+    pub fn push(&self, item: T) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let mut dropped = false;
+
+        if state.items.len() >= self.capacity {
+            match self.policy {
+                OverflowPolicy::Block => {
+                    while state.items.len() >= self.capacity {
+                        state = self.not_full.wait(state).unwrap();
+                    }
+                },
+                OverflowPolicy::DropNewest => return false,
+                OverflowPolicy::DropOldest => {
+                    state.items.pop_front();
+                    dropped = true;
+                },
+            }
+        }
+
+        state.items.push_back(item);
+        self.not_empty.notify_one();
+        !dropped
+    }
+
+    /// Takes the oldest item off of the queue, waiting for one if it's
+    /// empty. Returns `None` once the queue is closed and empty.
+    pub fn pop(&self) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            if let Some(item) = state.items.pop_front() {
+                self.not_full.notify_one();
+                return Some(item)
+            }
+            if state.closed {
+                return None
+            }
+            state = self.not_empty.wait(state).unwrap();
+        }
+    }
+
+    /// Marks that nothing more will be pushed. The consumer will still get
+    /// everything already in the queue.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.not_empty.notify_all();
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterator that pops items until the queue is closed and empty.
+    pub fn consume(queue: Arc<BoundedQueue<T>>) -> Consume<T> {
+        Consume { queue }
+    }
+}
+
+pub struct Consume<T> {
+    queue: Arc<BoundedQueue<T>>,
+}
+
+impl<T> Iterator for Consume<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.queue.pop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{BoundedQueue, OverflowPolicy};
+
+    #[test]
+    fn it_drops_newest_when_full() {
+        let queue = BoundedQueue::new(2, OverflowPolicy::DropNewest);
+        assert!(queue.push(1));
+        assert!(queue.push(2));
+        assert!(!queue.push(3));
+        queue.close();
+
+        assert_eq!(BoundedQueue::consume(Arc::new(queue)).collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn it_drops_oldest_when_full() {
+        let queue = BoundedQueue::new(2, OverflowPolicy::DropOldest);
+        assert!(queue.push(1));
+        assert!(queue.push(2));
+        assert!(!queue.push(3));
+        queue.close();
+
+        assert_eq!(BoundedQueue::consume(Arc::new(queue)).collect::<Vec<_>>(), vec![2, 3]);
+    }
+
+    #[test]
+    fn it_blocks_until_there_is_room() {
+        let queue = Arc::new(BoundedQueue::new(1, OverflowPolicy::Block));
+        assert!(queue.push(1));
+
+        let pushing = queue.clone();
+        let pusher = thread::spawn(move || {
+            pushing.push(2);
+            pushing.close();
+        });
+
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(queue.len(), 1);
+
+        assert_eq!(BoundedQueue::consume(queue).collect::<Vec<_>>(), vec![1, 2]);
+        pusher.join().unwrap();
+    }
+}
@@ -1,157 +1,234 @@
-use std::io::{BufRead, BufReader};
-use std::str;
-use std::sync::mpsc::{channel, Sender};
-use std::thread;
-use std::time::Duration;
-
-use std::net::{
-    TcpListener,
-    TcpStream,
-    ToSocketAddrs,
-    UdpSocket
-};
+#[cfg(unix)]
+use std::fs;
+#[cfg(unix)]
+use std::io;
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::net::{UnixDatagram, UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
 
+use super::listener::{protocol_listener, Batch, TcpLineListener, UdpDatagramListener};
+#[cfg(unix)]
+use super::listener::{
+    check_queue_capacity,
+    spawn_datagram_listener,
+    spawn_stream_listener,
+    ListenerError,
+    ListenerHandle,
+    POLL_INTERVAL,
+};
+#[cfg(unix)]
 use super::super::SharedStore;
 use super::super::parsers::statsd::parse_metrics;
 
-/// Listens on a TCP socket for StatsD messages.
-pub struct StatsdTcpListener {
-    store: SharedStore,
+pub use super::listener::MAX_DATAGRAM_SIZE;
+
+protocol_listener! {
+    /// Listens on a TCP socket for StatsD messages. See `TcpLineListener`
+    /// for its settings.
+    pub struct StatsdTcpListener(TcpLineListener, parse_message);
 }
 
-impl StatsdTcpListener {
-    pub fn new(store: SharedStore) -> StatsdTcpListener {
-        StatsdTcpListener {
-            store: store,
-        }
-    }
+protocol_listener! {
+    /// Listens for StatsD UDP datagrams. See `UdpDatagramListener` for its
+    /// settings.
+    pub struct StatsdUdpListener(UdpDatagramListener, parse_message);
+}
 
-    /// Spawns a separate thread to listen for TCP connections; connections
-    /// opened then spawn another thread that reads StatsD messages from the
-    /// client and sends those messages back to the calling thread.
-    ///
-    /// The calling thread blocks waiting for messages. When it receives a
-    /// message it parses its metrics and records those metrics in the store.
-    pub fn listen<A>(&self, addr: A)
-        where A: ToSocketAddrs {
-        let (send, recv) = channel();
+/// Whether a Unix socket receives datagrams or stream connections.
+#[cfg(unix)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UnixSocketType {
+    /// `SOCK_DGRAM`: each datagram holds one or more lines, like UDP.
+    Datagram,
+    /// `SOCK_STREAM`: clients connect and send lines, like TCP.
+    Stream,
+}
 
-        let listener = TcpListener::bind(addr).unwrap();
-        thread::spawn(move || {
-            StatsdTcpListener::accept_on_listener(listener, send)
-        });
+/// Listens on a Unix domain socket for StatsD messages. Unix datagram
+/// sockets don't drop messages the way UDP does when the receiver falls
+/// behind, which makes them a good fit for sidecars.
+///
+/// A stale socket file left behind at the path (one nothing is listening on
+/// anymore) is removed when the listener starts, and the socket file is
+/// removed again when the listener stops.
+#[cfg(unix)]
+pub struct StatsdUnixListener {
+    pub socket_type: UnixSocketType,
+    /// Mode to give the socket file once it's created (eg. `0o660` to only
+    /// let a group write to it). Otherwise the process' umask decides.
+    pub permissions: Option<u32>,
+    /// Settings and counters for a stream socket, which is read just like
+    /// a TCP socket. `spawn` only takes the path though.
+    pub stream: StatsdTcpListener,
+    /// Settings and counters for a datagram socket, which is read just like
+    /// a UDP socket except that it only has the one socket:
+    /// `recv_buffer_size` and `receive_threads` aren't used.
+    pub datagram: StatsdUdpListener,
+}
 
-        for line in recv {
-            handle_line(&self.store, line)
+#[cfg(unix)]
+impl StatsdUnixListener {
+    pub fn new(store: SharedStore, socket_type: UnixSocketType) -> StatsdUnixListener {
+        StatsdUnixListener {
+            socket_type,
+            permissions: None,
+            stream: StatsdTcpListener::new(store.clone()),
+            datagram: StatsdUdpListener::new(store),
         }
     }
 
-    fn accept_on_listener(listener: TcpListener, send: Sender<String>) {
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    // Clients have 30 seconds to send us data before we'll drop.
-                    let _ = stream.set_read_timeout(Some(Duration::from_secs(30)));
-
-                    let send = send.clone();
+    /// Listens on the socket, blocking the calling thread until the listener
+    /// fails. Use `spawn` to be able to stop the listener.
+    pub fn listen<P: AsRef<Path>>(&self, path: P) -> Result<(), ListenerError> {
+        self.spawn(path)?.join()
+    }
 
-                    thread::spawn(move || {
-                        StatsdTcpListener::handle_client(stream, send)
-                    });
-                },
-                Err(e) => panic!("Failed to listen on TCP socket: {}", e),
-            }
+    /// Creates the socket and spawns threads to receive messages on it and
+    /// record their metrics in the store, like the TCP and UDP listeners.
+    pub fn spawn<P: AsRef<Path>>(&self, path: P) -> Result<ListenerHandle, ListenerError> {
+        let path = path.as_ref();
+        if self.socket_type == UnixSocketType::Stream {
+            check_queue_capacity(self.stream.queue_capacity)?;
         }
-    }
+        remove_stale_socket(path, self.socket_type).map_err(ListenerError::Bind)?;
 
-    fn handle_client(stream: TcpStream, send: Sender<String>) {
-        let mut reader = BufReader::new(stream);
-
-        loop {
-            let mut line = String::new();
-
-            match reader.read_line(&mut line) {
-                Err(err) => {
-                    println!("Error reading StatsD line: {:?}", err);
-                    break
-                },
-                Ok(0) => {
-                    // Close if there are no more bytes.
-                    break
-                },
-                Ok(_) => {
-                    send.send(line).unwrap()
-                },
-            }
+        match self.socket_type {
+            UnixSocketType::Datagram => self.spawn_datagram(path),
+            UnixSocketType::Stream   => self.spawn_stream(path),
         }
-    } // fn handle_client
-} // struct StatsdTcpListener
+    }
 
-/// Listens for StatsD UDP datagrams.
-pub struct StatsdUdpListener {
-    store: SharedStore,
-}
+    fn spawn_datagram(&self, path: &Path) -> Result<ListenerHandle, ListenerError> {
+        let socket = UnixDatagram::bind(path).map_err(ListenerError::Bind)?;
+        let socket_file = SocketFile(path.to_owned());
+        self.set_permissions(path)?;
+        socket.set_read_timeout(Some(POLL_INTERVAL)).map_err(ListenerError::Io)?;
 
-impl StatsdUdpListener {
-    pub fn new(store: SharedStore) -> StatsdUdpListener {
-        StatsdUdpListener {
-            store: store,
-        }
+        // The socket file is removed once the receiving thread is done
+        // with the socket.
+        let recv = move |buf: &mut [u8]| {
+            let _ = &socket_file;
+            socket.recv(buf)
+        };
+        Ok(spawn_datagram_listener(vec![recv], self.datagram.datagram_options(), None))
     }
 
-    /// Spawns a separate thread that listens for StatsD UDP datagrams,
-    /// received datagrams are sent back to the calling thread (this will
-    /// block) and the parsed metrics are recorded in the store.
-    pub fn listen<A>(&self, addr: A)
-        where A: ToSocketAddrs {
-        let (send, recv) = channel();
+    fn spawn_stream(&self, path: &Path) -> Result<ListenerHandle, ListenerError> {
+        let listener = UnixListener::bind(path).map_err(ListenerError::Bind)?;
+        let socket_file = SocketFile(path.to_owned());
+        self.set_permissions(path)?;
+        listener.set_nonblocking(true).map_err(ListenerError::Io)?;
 
-        let socket = UdpSocket::bind(addr).unwrap();
+        // Like with datagrams the socket file is removed once the accepting
+        // thread is done.
+        let accept = move || {
+            let _ = &socket_file;
+            listener.accept().map(|(stream, _)| stream)
+        };
+        Ok(spawn_stream_listener(accept, self.stream.stream_options(), None))
+    }
 
-        thread::spawn(move || {
-            let mut buf = [0; 1024];
-            loop {
-                let (bytes_read, _) = match socket.recv_from(&mut buf) {
-                    Ok(pair) => pair,
-                    Err(_) => return,
-                };
+    fn set_permissions(&self, path: &Path) -> Result<(), ListenerError> {
+        match self.permissions {
+            Some(mode) => fs::set_permissions(path, fs::Permissions::from_mode(mode)).map_err(ListenerError::Io),
+            None => Ok(()),
+        }
+    }
+} // impl StatsdUnixListener
 
-                // Get a string from just the amount of bytes read.
-                let message: &str = match str::from_utf8(&buf[..bytes_read]) {
-                    Ok(s) => s,
-                    Err(_) => return,
-                };
+/// Removes the socket file when dropped.
+#[cfg(unix)]
+struct SocketFile(PathBuf);
 
-                send.send(message.to_owned()).unwrap();
-            }
-        });
+#[cfg(unix)]
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
 
-        for line in recv {
-            handle_line(&self.store, line)
-        }
-    } // fn listen
-} // impl StatsdUdpListener
+/// Removes a socket file left behind by a listener that's gone away. It's an
+/// error if something else is still listening on the socket or if the path
+/// isn't a socket at all.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path, socket_type: UnixSocketType) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
 
-fn handle_line(store: &SharedStore, line: String) {
-    let line_trimmed = line.trim_end();
-    let result = parse_metrics(line_trimmed.as_bytes());
+    if !metadata.file_type().is_socket() {
+        let message = format!("{} already exists and isn't a socket", path.display());
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, message))
+    }
 
-    match result {
-        Ok(metrics) => {
-            let metrics = metrics.iter().map(|m| m.to_standard_metric()).collect();
-            store.record(metrics)
-        },
-        Err(err) => {
-            println!("{:?}", err)
+    let connected = match socket_type {
+        UnixSocketType::Datagram => UnixDatagram::unbound().and_then(|socket| socket.connect(path)),
+        UnixSocketType::Stream   => UnixStream::connect(path).map(|_| ()),
+    };
+
+    match connected {
+        Ok(()) => {
+            let message = format!("{} is already being listened on", path.display());
+            Err(io::Error::new(io::ErrorKind::AddrInUse, message))
         },
+        Err(ref err) if err.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+        Err(err) => Err(err),
     }
 }
 
+/// Parses all the metrics, events, and service checks in a StatsD message
+/// into the batch, returning the number of malformed metrics in it.
+/// Listeners count those rather than logging each one, so that a flood of
+/// bad datagrams doesn't turn into a flood of writes to stdout.
+pub(crate) fn parse_message(batch: &mut Batch, message: &str) -> usize {
+    let parsed = parse_metrics(message.trim_end().as_bytes());
+
+    batch.metrics.extend(parsed.metrics.iter().map(|m| m.to_standard_metric()));
+    batch.events.extend(parsed.events);
+    batch.service_checks.extend(parsed.service_checks);
+
+    parsed.errors.len()
+}
+
 #[cfg(test)]
 mod tests {
-    use super::handle_line;
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream, UdpSocket};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[cfg(unix)]
+    use std::{env, fs, process};
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
+    #[cfg(unix)]
+    use std::os::unix::net::{UnixDatagram, UnixStream};
+    #[cfg(unix)]
+    use std::path::PathBuf;
+
+    use super::{parse_message, StatsdTcpListener, StatsdUdpListener};
+    #[cfg(unix)]
+    use super::{StatsdUnixListener, UnixSocketType};
+    use super::super::listener::{handle_message, ListenerError};
     use super::super::super::SharedStore;
-    use super::super::super::metrics::{AggregatedMetrics, AggregatedMetricType, Dimension};
+    use super::super::super::metrics::{
+        AggregatedMetrics,
+        AggregatedMetricType,
+        Dimension,
+        Event,
+        ServiceCheck,
+        ServiceCheckStatus,
+    };
+
+    fn handle_line(store: &SharedStore, line: String) -> usize {
+        handle_message(store, parse_message, &line)
+    }
 
     #[test]
     fn handle_line_parses_metrics() {
@@ -164,4 +241,256 @@ mod tests {
             (AggregatedMetricType::Sample, Dimension::with_name("foo"), 1.0),
         ]));
     }
+
+    #[test]
+    fn handle_line_records_valid_metrics_despite_errors() {
+        let store = SharedStore::new();
+        let errors = handle_line(&store, "foo:1|c\nfoo:oops|c\nfoo:2|c".to_owned());
+
+        assert_eq!(errors, 1);
+        assert_eq!(store.flush(), AggregatedMetrics::with_metrics(vec![
+            (AggregatedMetricType::Count, Dimension::with_name("foo"), 3.0),
+        ]));
+    }
+
+    #[test]
+    fn handle_line_records_events_and_service_checks() {
+        let store = SharedStore::new();
+        let errors = handle_line(&store, "_e{6,4}:Deploy|v1.2\n_sc|app|1".to_owned());
+
+        assert_eq!(errors, 0);
+
+        let flushed = store.flush();
+        assert_eq!(flushed.events(), &[Event::new("Deploy", "v1.2")]);
+        assert_eq!(flushed.service_checks(), &[ServiceCheck::new("app", ServiceCheckStatus::Warning)]);
+    }
+
+    #[test]
+    fn udp_listener_survives_invalid_datagrams() {
+        let store = SharedStore::new();
+        let mut listener = StatsdUdpListener::new(store.clone());
+        listener.max_datagram_size = 16;
+        listener.recv_buffer_size = Some(1 << 16);
+
+        let handle = listener.spawn("127.0.0.1:0").unwrap();
+        let addr = handle.local_addr().unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(&[0xff, 0xfe], addr).unwrap();
+        client.send_to(b"foo:1|c\nbar:2|c\nbaz:3|c", addr).unwrap();
+        client.send_to(b"foo:1|c", addr).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        handle.shutdown().unwrap();
+
+        assert_eq!(listener.invalid_datagram_count(), 2);
+        assert_eq!(store.flush(), AggregatedMetrics::with_metrics(vec![
+            (AggregatedMetricType::Count, Dimension::with_name("foo"), 1.0),
+        ]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn udp_listener_receives_on_several_sockets() {
+        let store = SharedStore::new();
+        let mut listener = StatsdUdpListener::new(store.clone());
+        listener.receive_threads = 4;
+        listener.batch_size = 10;
+
+        let handle = listener.spawn("127.0.0.1:0").unwrap();
+        let addr = handle.local_addr().unwrap();
+
+        // Different source ports so the kernel spreads them across sockets.
+        for _ in 0..8 {
+            let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+            for _ in 0..25 {
+                client.send_to(b"foo:1|c\nbar:2|c", addr).unwrap();
+            }
+        }
+        thread::sleep(Duration::from_millis(100));
+        handle.shutdown().unwrap();
+
+        let flushed = store.flush();
+        assert!(flushed.iter().any(|m| *m == (AggregatedMetricType::Count, Dimension::with_name("foo"), 200.0)));
+        assert!(flushed.iter().any(|m| *m == (AggregatedMetricType::Count, Dimension::with_name("bar"), 400.0)));
+    }
+
+    #[test]
+    fn tcp_listener_records_received_lines_on_shutdown() {
+        let store = SharedStore::new();
+        let listener = StatsdTcpListener::new(store.clone());
+
+        let handle = listener.spawn("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(handle.local_addr().unwrap()).unwrap();
+        client.write_all(b"foo:1|c\nfoo:2|c\nbar:").unwrap();
+        client.write_all(b"3|c").unwrap();
+        client.flush().unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        // The client is still connected; shutting down closes it and still
+        // records its unterminated last line.
+        handle.shutdown().unwrap();
+
+        let flushed = store.flush();
+        assert_eq!(flushed.len(), 2);
+        assert!(flushed.iter().any(|m| *m == (AggregatedMetricType::Count, Dimension::with_name("foo"), 3.0)));
+        assert!(flushed.iter().any(|m| *m == (AggregatedMetricType::Count, Dimension::with_name("bar"), 3.0)));
+    }
+
+    #[test]
+    fn tcp_listener_shuts_down_while_a_client_keeps_sending() {
+        let store = SharedStore::new();
+        let listener = StatsdTcpListener::new(store.clone());
+
+        let handle = listener.spawn("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(handle.local_addr().unwrap()).unwrap();
+        let sending = thread::spawn(move || {
+            while client.write_all(b"foo:1|c\n").is_ok() {
+                thread::sleep(Duration::from_millis(10));
+            }
+        });
+        thread::sleep(Duration::from_millis(100));
+
+        let (done, shut_down) = mpsc::channel();
+        thread::spawn(move || done.send(handle.shutdown()).unwrap());
+        shut_down.recv_timeout(Duration::from_secs(2)).expect("listener didn't shut down").unwrap();
+
+        sending.join().unwrap();
+        assert_eq!(store.flush().len(), 1);
+    }
+
+    #[test]
+    fn tcp_listener_rejects_connections_over_the_limit() {
+        let store = SharedStore::new();
+        let mut listener = StatsdTcpListener::new(store.clone());
+        listener.max_connections = Some(1);
+        listener.idle_timeout = Duration::from_millis(300);
+
+        let handle = listener.spawn("127.0.0.1:0").unwrap();
+        let addr = handle.local_addr().unwrap();
+
+        let mut first = TcpStream::connect(addr).unwrap();
+        thread::sleep(Duration::from_millis(150));
+        let mut second = TcpStream::connect(addr).unwrap();
+        thread::sleep(Duration::from_millis(150));
+
+        first.write_all(b"foo:1|c\n").unwrap();
+        let _ = second.write_all(b"bar:1|c\n");
+
+        // The first client is dropped once it goes idle, letting in others.
+        thread::sleep(Duration::from_millis(600));
+        let mut third = TcpStream::connect(addr).unwrap();
+        third.write_all(b"baz:1|c\n").unwrap();
+        thread::sleep(Duration::from_millis(150));
+        handle.shutdown().unwrap();
+
+        assert_eq!(listener.rejected_connection_count(), 1);
+        let flushed = store.flush();
+        assert_eq!(flushed.len(), 2);
+        assert!(flushed.iter().all(|m| m.1.name != "bar"));
+    }
+
+    #[cfg(unix)]
+    fn socket_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("metrics_distributor_{}_{}.sock", process::id(), name))
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_datagram_listener_replaces_stale_socket() {
+        let path = socket_path("dgram");
+        // Leaves a socket file behind that nothing is listening on.
+        drop(UnixDatagram::bind(&path).unwrap());
+
+        let store = SharedStore::new();
+        let mut listener = StatsdUnixListener::new(store.clone(), UnixSocketType::Datagram);
+        listener.permissions = Some(0o620);
+        let handle = listener.spawn(&path).unwrap();
+
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o620);
+
+        let client = UnixDatagram::unbound().unwrap();
+        client.send_to(b"foo:1|c\nfoo:2|c", &path).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        handle.shutdown().unwrap();
+
+        assert!(!path.exists());
+        assert_eq!(store.flush(), AggregatedMetrics::with_metrics(vec![
+            (AggregatedMetricType::Count, Dimension::with_name("foo"), 3.0),
+        ]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_stream_listener_records_lines() {
+        let path = socket_path("stream");
+        let store = SharedStore::new();
+        let listener = StatsdUnixListener::new(store.clone(), UnixSocketType::Stream);
+        let handle = listener.spawn(&path).unwrap();
+
+        // Can't start a second listener on a socket that's in use.
+        match StatsdUnixListener::new(SharedStore::new(), UnixSocketType::Stream).spawn(&path) {
+            Err(ListenerError::Bind(_)) => (),
+            other => panic!("Expected bind error, got {:?}", other.map(|_| ())),
+        }
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"foo:1|c\nbar:2|g\n").unwrap();
+        drop(client);
+        thread::sleep(Duration::from_millis(50));
+        handle.shutdown().unwrap();
+
+        assert_eq!(store.flush().len(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_listener_refuses_to_replace_other_files() {
+        let path = socket_path("file");
+        fs::write(&path, "not a socket").unwrap();
+
+        let listener = StatsdUnixListener::new(SharedStore::new(), UnixSocketType::Datagram);
+        assert!(listener.spawn(&path).is_err());
+        assert!(path.exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn tcp_listener_rejects_an_empty_queue() {
+        let mut listener = StatsdTcpListener::new(SharedStore::new());
+        listener.queue_capacity = 0;
+
+        match listener.spawn("127.0.0.1:0") {
+            Err(ListenerError::InvalidOption(_)) => (),
+            other => panic!("Expected invalid option error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn listener_reports_bind_errors() {
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let listener = StatsdTcpListener::new(SharedStore::new());
+
+        match listener.listen(taken.local_addr().unwrap()) {
+            Err(ListenerError::Bind(_)) => (),
+            other => panic!("Expected bind error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn handle_line_aggregates_per_tag_set() {
+        let store = SharedStore::new();
+        handle_line(&store, "foo:1|c|#env:prod".to_owned());
+        handle_line(&store, "foo:2|c|#env:prod".to_owned());
+        handle_line(&store, "foo:4|c|#env:staging".to_owned());
+
+        let mut prod = Dimension::with_name("foo");
+        prod.tags.insert("env".to_owned(), "prod".to_owned());
+        let mut staging = Dimension::with_name("foo");
+        staging.tags.insert("env".to_owned(), "staging".to_owned());
+
+        let flushed = store.flush();
+        assert_eq!(flushed.len(), 2);
+        assert!(flushed.iter().any(|m| *m == (AggregatedMetricType::Count, prod.clone(), 3.0)));
+        assert!(flushed.iter().any(|m| *m == (AggregatedMetricType::Count, staging.clone(), 4.0)));
+    }
 }
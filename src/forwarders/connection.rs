@@ -0,0 +1,68 @@
+//! TCP connection shared by the forwarders that stream lines to a server.
+
+use std::io::{self, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// TCP connection that's kept open between flushes. If it has been closed
+/// or a write fails then it reconnects and tries once more.
+pub(crate) struct PersistentConnection {
+    stream: Mutex<Option<TcpStream>>,
+}
+
+impl PersistentConnection {
+    pub fn new() -> PersistentConnection {
+        PersistentConnection {
+            stream: Mutex::new(None),
+        }
+    }
+
+    /// Writes all of `data`, connecting to `address` first if need be.
+    pub fn send(&self, address: &str, timeout: Duration, data: &[u8]) -> io::Result<()> {
+        let mut connection = self.stream.lock().unwrap();
+        let mut result = Ok(());
+
+        for _ in 0..2 {
+            if connection.as_ref().map_or(true, is_closed) {
+                *connection = Some(connect(address, timeout)?);
+            }
+
+            let stream = connection.as_mut().unwrap();
+            result = stream.write_all(data).and_then(|_| stream.flush());
+            if result.is_ok() { break }
+            *connection = None;
+        }
+
+        result
+    }
+}
+
+fn connect(address: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to");
+
+    for addr in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => {
+                stream.set_write_timeout(Some(timeout))?;
+                return Ok(stream)
+            },
+            Err(err) => last_error = err,
+        }
+    }
+
+    Err(last_error)
+}
+
+/// The servers we stream to never write to us, so a connection we can read
+/// an end-of-file from has been closed by the other end.
+fn is_closed(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() { return true }
+    let mut buf = [0; 1];
+    let closed = match stream.peek(&mut buf) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(ref err) => err.kind() != io::ErrorKind::WouldBlock,
+    };
+    closed || stream.set_nonblocking(false).is_err()
+}
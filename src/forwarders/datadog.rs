@@ -7,7 +7,7 @@ use rustc_serialize::json::{self, Json, ToJson};
 use std::collections::BTreeMap;
 
 use super::Forwarder;
-use super::super::metrics::AggregatedMetrics;
+use super::super::metrics::{AggregatedMetrics, Dimension, Event, ServiceCheck, Tags};
 
 /// Forwards metrics to Datadog via its HTTPS API.
 ///
@@ -43,6 +43,7 @@ impl DatadogForwarder {
                     Count   => "count",
                     Measure => "gauge",
                     Sample  => "gauge",
+                    Set     => "gauge",
                 };
 
                 object.insert("metric".to_owned(), dim.name.to_json());
@@ -51,10 +52,9 @@ impl DatadogForwarder {
                     Json::Array(vec![ timestamp.to_json(), value.to_json() ]),
                 ]));
 
-                if let Some(ref source) = dim.source {
-                    object.insert("tags".to_owned(), Json::Array(vec![
-                        format!("source:{}", source).to_json()
-                    ]));
+                let tags = DatadogForwarder::serialize_tags(dim);
+                if !tags.is_empty() {
+                    object.insert("tags".to_owned(), Json::Array(tags));
                 }
 
                 object.to_json()
@@ -68,20 +68,117 @@ impl DatadogForwarder {
         data.to_json()
     }
 
+    /// Serializes distribution points for Datadog's distribution API, which
+    /// computes percentiles across all the points it receives.
+    fn serialize_distributions(metrics: &AggregatedMetrics) -> Json {
+        let timestamp = UTC::now().timestamp();
+
+        let series: Vec<Json> = metrics
+            .distributions()
+            .map(|(dim, values)| {
+                let mut object: BTreeMap<String, Json> = BTreeMap::new();
+
+                object.insert("metric".to_owned(), dim.name.to_json());
+                object.insert("points".to_owned(), Json::Array(vec![
+                    Json::Array(vec![ timestamp.to_json(), values.to_json() ]),
+                ]));
+
+                let tags = DatadogForwarder::serialize_tags(dim);
+                if !tags.is_empty() {
+                    object.insert("tags".to_owned(), Json::Array(tags));
+                }
+
+                object.to_json()
+            })
+            .collect();
+
+        let mut data: BTreeMap<String, Json> = BTreeMap::new();
+        data.insert("series".to_owned(), Json::Array(series));
+
+        data.to_json()
+    }
+
+    fn serialize_event(event: &Event) -> Json {
+        let mut object: BTreeMap<String, Json> = BTreeMap::new();
+
+        object.insert("title".to_owned(), event.title.to_json());
+        object.insert("text".to_owned(), event.text.to_json());
+
+        if let Some(timestamp) = event.timestamp {
+            object.insert("date_happened".to_owned(), timestamp.to_json());
+        }
+        if let Some(ref hostname) = event.hostname {
+            object.insert("host".to_owned(), hostname.to_json());
+        }
+        if let Some(ref aggregation_key) = event.aggregation_key {
+            object.insert("aggregation_key".to_owned(), aggregation_key.to_json());
+        }
+        if let Some(ref priority) = event.priority {
+            object.insert("priority".to_owned(), priority.to_json());
+        }
+        if let Some(ref source_type_name) = event.source_type_name {
+            object.insert("source_type_name".to_owned(), source_type_name.to_json());
+        }
+        if let Some(ref alert_type) = event.alert_type {
+            object.insert("alert_type".to_owned(), alert_type.to_json());
+        }
+        if !event.tags.is_empty() {
+            object.insert("tags".to_owned(), Json::Array(DatadogForwarder::serialize_tag_map(&event.tags)));
+        }
+
+        object.to_json()
+    }
+
+    fn serialize_service_check(check: &ServiceCheck) -> Json {
+        let mut object: BTreeMap<String, Json> = BTreeMap::new();
+
+        object.insert("check".to_owned(), check.name.to_json());
+        object.insert("status".to_owned(), (check.status as u8).to_json());
+
+        if let Some(timestamp) = check.timestamp {
+            object.insert("timestamp".to_owned(), timestamp.to_json());
+        }
+        if let Some(ref hostname) = check.hostname {
+            object.insert("host_name".to_owned(), hostname.to_json());
+        }
+        if let Some(ref message) = check.message {
+            object.insert("message".to_owned(), message.to_json());
+        }
+        object.insert("tags".to_owned(), Json::Array(DatadogForwarder::serialize_tag_map(&check.tags)));
+
+        object.to_json()
+    }
+
+    /// Datadog tags are `key:value` strings; the dimension's source is sent
+    /// as a `source` tag.
+    fn serialize_tags(dim: &Dimension) -> Vec<Json> {
+        let source = dim.source.iter().map(|source| format!("source:{}", source).to_json());
+
+        source.chain(DatadogForwarder::serialize_tag_map(&dim.tags)).collect()
+    }
+
+    /// Tags with an empty value are sent as just their key.
+    fn serialize_tag_map(tags: &Tags) -> Vec<Json> {
+        tags.iter()
+            .map(|(key, value)| {
+                if value.is_empty() {
+                    key.to_json()
+                } else {
+                    format!("{}:{}", key, value).to_json()
+                }
+            })
+            .collect()
+    }
+
     fn post<'a>(&'a self, client: &'a Client, path: &str) -> RequestBuilder {
         let path = format!("{}{}?api_key={}", self.base_url, path, self.api_key);
 
         client.post(&path)
             .header(CONTENT_TYPE, "application/json")
     }
-}
 
-impl Forwarder for DatadogForwarder {
-    fn forward_metrics(&self, metrics: AggregatedMetrics) {
-        let body = json::encode(&DatadogForwarder::serialize_metrics(metrics)).unwrap();
-        let client = Client::new();
-
-        let res = self.post(&client, "/v1/series")
+    fn send(&self, client: &Client, path: &str, body: String) {
+        let res = self.post(client, path)
             .body(body)
             .send();
 
@@ -98,6 +195,40 @@ impl Forwarder for DatadogForwarder {
     }
 }
 
+impl Forwarder for DatadogForwarder {
+    fn forward_metrics(&self, metrics: AggregatedMetrics) {
+        let client = Client::new();
+
+        if metrics.distributions().len() > 0 {
+            let body = json::encode(&DatadogForwarder::serialize_distributions(&metrics)).unwrap();
+            self.send(&client, "/v1/distribution_points", body);
+        }
+
+        let body = json::encode(&DatadogForwarder::serialize_metrics(metrics)).unwrap();
+        self.send(&client, "/v1/series", body);
+    }
+
+    /// Posts each event to the events API.
+    fn forward_events(&self, events: &[Event]) {
+        let client = Client::new();
+
+        for event in events {
+            let body = json::encode(&DatadogForwarder::serialize_event(event)).unwrap();
+            self.send(&client, "/v1/events", body);
+        }
+    }
+
+    /// Posts each service check to the check run API.
+    fn forward_service_checks(&self, service_checks: &[ServiceCheck]) {
+        let client = Client::new();
+
+        for check in service_checks {
+            let body = json::encode(&DatadogForwarder::serialize_service_check(check)).unwrap();
+            self.send(&client, "/v1/check_run", body);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::DatadogForwarder;
@@ -105,6 +236,9 @@ mod tests {
         AggregatedMetrics,
         AggregatedMetricType,
         Dimension,
+        Event,
+        ServiceCheck,
+        ServiceCheckStatus,
     };
 
     use rustc_serialize::json::ToJson;
@@ -147,4 +281,78 @@ mod tests {
         let ref tag = tags[0];
         assert_eq!(tag, &"source:test_source".to_json());
     }
+
+    #[test]
+    fn datadog_forwarder_serializes_distributions() {
+        let mut metrics = AggregatedMetrics::new();
+        metrics.aggregate_distributions(vec![
+            (Dimension::with_name("latency").tagged("env", "prod"), vec![1.0, 2.5]),
+        ].into_iter());
+        let json = DatadogForwarder::serialize_distributions(&metrics);
+
+        let series = json.find("series").and_then(|s| s.as_array()).unwrap();
+        assert_eq!(series.len(), 1);
+
+        let item = series[0].as_object().unwrap();
+        assert_eq!(item.get("metric"), Some(&"latency".to_json()));
+        assert_eq!(item.get("tags"), Some(&vec!["env:prod".to_json()].to_json()));
+
+        let points = item.get("points").unwrap().as_array().unwrap();
+        let point = points[0].as_array().unwrap();
+        assert_eq!(point[1], vec![1.0, 2.5].to_json());
+    }
+
+    #[test]
+    fn datadog_forwarder_serializes_events() {
+        let mut event = Event::new("Deploy", "v1.2");
+        event.timestamp = Some(1500000000);
+        event.alert_type = Some("success".to_owned());
+        event.tags.insert("env".to_owned(), "prod".to_owned());
+
+        let json = DatadogForwarder::serialize_event(&event);
+        let object = json.as_object().unwrap();
+
+        assert_eq!(object.get("title"), Some(&"Deploy".to_json()));
+        assert_eq!(object.get("text"), Some(&"v1.2".to_json()));
+        assert_eq!(object.get("date_happened"), Some(&1500000000.to_json()));
+        assert_eq!(object.get("alert_type"), Some(&"success".to_json()));
+        assert_eq!(object.get("tags"), Some(&vec!["env:prod".to_json()].to_json()));
+        assert!(object.get("host").is_none());
+    }
+
+    #[test]
+    fn datadog_forwarder_serializes_service_checks() {
+        let mut check = ServiceCheck::new("app.health", ServiceCheckStatus::Critical);
+        check.hostname = Some("web.1".to_owned());
+        check.message = Some("down".to_owned());
+
+        let json = DatadogForwarder::serialize_service_check(&check);
+        let object = json.as_object().unwrap();
+
+        assert_eq!(object.get("check"), Some(&"app.health".to_json()));
+        assert_eq!(object.get("status"), Some(&2u8.to_json()));
+        assert_eq!(object.get("host_name"), Some(&"web.1".to_json()));
+        assert_eq!(object.get("message"), Some(&"down".to_json()));
+    }
+
+    #[test]
+    fn datadog_forwarder_serializes_tags() {
+        let mut dim = Dimension::with_name_and_source("test_count", "test_source");
+        dim.tags.insert("env".to_owned(), "prod".to_owned());
+        dim.tags.insert("beta".to_owned(), "".to_owned());
+
+        let metrics = AggregatedMetrics::with_metrics(vec![
+            (AggregatedMetricType::Count, dim, 1.0),
+        ]);
+        let json = DatadogForwarder::serialize_metrics(metrics);
+
+        let series = json.find("series").and_then(|s| s.as_array()).unwrap();
+        let tags = series[0].find("tags").and_then(|t| t.as_array()).unwrap();
+
+        assert_eq!(tags, &vec![
+            "source:test_source".to_json(),
+            "beta".to_json(),
+            "env:prod".to_json(),
+        ]);
+    }
 }
@@ -0,0 +1,168 @@
+//! Writes metrics to Carbon using the [Graphite plaintext protocol][].
+//!
+//! [Graphite plaintext protocol]: https://graphite.readthedocs.io/en/latest/feeding-carbon.html#the-plaintext-protocol
+
+use std::time::Duration;
+
+use chrono::UTC;
+
+use super::Forwarder;
+use super::connection::PersistentConnection;
+use super::super::metrics::{AggregatedMetrics, Dimension};
+
+/// Where a dimension's source goes in the Graphite path.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SourceHandling {
+    /// Leave the source out entirely.
+    Ignore,
+    /// `source.name`, eg. `web1.requests`.
+    Prepend,
+    /// `name.source`, eg. `requests.web1`.
+    Append,
+    /// Send it as a `source` tag: `requests;source=web1`.
+    Tag,
+}
+
+/// Forwards metrics to Carbon as `path value timestamp` lines over a TCP
+/// connection that's kept open between flushes. If the connection has been
+/// closed or a write fails then it reconnects and tries once more before
+/// giving up on that flush.
+///
+/// Tags are sent as Graphite 1.1 style tags (`name;key=value`). Graphite
+/// has no notion of distributions so those aren't sent.
+pub struct GraphiteForwarder {
+    pub address: String,
+    /// Prepended (with a `.`) to every metric's path.
+    pub prefix: Option<String>,
+    pub source_handling: SourceHandling,
+    /// How long to wait when connecting and writing.
+    pub timeout: Duration,
+    connection: PersistentConnection,
+}
+
+impl GraphiteForwarder {
+    pub fn new(address: &str) -> GraphiteForwarder {
+        GraphiteForwarder {
+            address: address.to_owned(),
+            prefix: None,
+            source_handling: SourceHandling::Prepend,
+            timeout: Duration::from_secs(5),
+            connection: PersistentConnection::new(),
+        }
+    }
+
+    fn serialize_metrics(&self, metrics: &AggregatedMetrics, timestamp: i64) -> String {
+        let mut lines = String::new();
+
+        for &(_, ref dim, value) in metrics.iter() {
+            lines.push_str(&format!("{} {} {}\n", self.path(dim), value, timestamp));
+        }
+
+        lines
+    }
+
+    fn path(&self, dim: &Dimension) -> String {
+        let mut path = String::new();
+
+        if let Some(ref prefix) = self.prefix {
+            path.push_str(prefix);
+            path.push('.');
+        }
+
+        let name = sanitize(&dim.name);
+        match (self.source_handling, dim.source.as_ref()) {
+            (SourceHandling::Prepend, Some(source)) => path.push_str(&format!("{}.{}", sanitize(source), name)),
+            (SourceHandling::Append, Some(source))  => path.push_str(&format!("{}.{}", name, sanitize(source))),
+            _ => path.push_str(&name),
+        }
+
+        if self.source_handling == SourceHandling::Tag {
+            if let Some(ref source) = dim.source {
+                path.push_str(&format!(";source={}", sanitize_tag(source)));
+            }
+        }
+        for (key, value) in dim.tags.iter() {
+            if value.is_empty() { continue }
+            path.push_str(&format!(";{}={}", sanitize_tag(key), sanitize_tag(value)));
+        }
+
+        path
+    }
+}
+
+impl Forwarder for GraphiteForwarder {
+    fn forward_metrics(&self, metrics: AggregatedMetrics) {
+        let lines = self.serialize_metrics(&metrics, UTC::now().timestamp());
+        if lines.is_empty() { return }
+
+        if let Err(err) = self.connection.send(&self.address, self.timeout, lines.as_bytes()) {
+            println!("Graphite Error: {:?}", err)
+        }
+    }
+}
+
+/// Paths can't contain whitespace.
+fn sanitize(name: &str) -> String {
+    name.chars().map(|c| if c.is_whitespace() { '_' } else { c }).collect()
+}
+
+/// Tags can't contain whitespace or any of `;!^=`.
+fn sanitize_tag(tag: &str) -> String {
+    tag.chars().map(|c| if c.is_whitespace() || ";!^=".contains(c) { '_' } else { c }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{GraphiteForwarder, SourceHandling};
+    use super::super::Forwarder;
+    use super::super::super::metrics::{AggregatedMetrics, AggregatedMetricType, Dimension};
+
+    #[test]
+    fn it_serializes_paths() {
+        let mut forwarder = GraphiteForwarder::new("localhost:2003");
+        forwarder.prefix = Some("stats".to_owned());
+        let dim = Dimension::with_name_and_source("http requests", "web1").tagged("status", "200");
+
+        assert_eq!(forwarder.path(&dim), "stats.web1.http_requests;status=200");
+
+        forwarder.source_handling = SourceHandling::Append;
+        assert_eq!(forwarder.path(&dim), "stats.http_requests.web1;status=200");
+
+        forwarder.source_handling = SourceHandling::Tag;
+        assert_eq!(forwarder.path(&dim), "stats.http_requests;source=web1;status=200");
+
+        forwarder.source_handling = SourceHandling::Ignore;
+        assert_eq!(forwarder.path(&Dimension::with_name_and_source("load", "web1")), "stats.load");
+    }
+
+    #[test]
+    fn it_reconnects_after_the_connection_is_closed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let forwarder = GraphiteForwarder::new(&listener.local_addr().unwrap().to_string());
+
+        let metrics = || AggregatedMetrics::with_metrics(vec![
+            (AggregatedMetricType::Sample, Dimension::with_name("load"), 0.5),
+        ]);
+
+        forwarder.forward_metrics(metrics());
+        let (first, _) = listener.accept().unwrap();
+        let mut line = String::new();
+        BufReader::new(&first).read_line(&mut line).unwrap();
+        assert!(line.starts_with("load 0.5 "));
+
+        // Carbon restarting closes the connection.
+        drop(first);
+        thread::sleep(Duration::from_millis(50));
+
+        forwarder.forward_metrics(metrics());
+        let (second, _) = listener.accept().unwrap();
+        let mut line = String::new();
+        BufReader::new(&second).read_line(&mut line).unwrap();
+        assert!(line.starts_with("load 0.5 "));
+    }
+}
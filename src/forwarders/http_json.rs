@@ -0,0 +1,283 @@
+//! POSTs each flush as a JSON document in the simple metrics format:
+//!
+//! ```json
+//! {
+//!   "timestamp": 1500000000,
+//!   "metrics": [
+//!     {
+//!       "type": "count",
+//!       "name": "http.requests",
+//!       "source": "web.1",
+//!       "tags": {"status": "200"},
+//!       "value": 42.0,
+//!       "timestamp": 1500000000
+//!     }
+//!   ],
+//!   "distributions": [
+//!     {"name": "upload.size", "tags": {}, "values": [512.0, 2048.0], "timestamp": 1500000000}
+//!   ],
+//!   "events": [
+//!     {"title": "Deploy", "text": "v1.2", "tags": {}, "timestamp": 1500000000}
+//!   ],
+//!   "service_checks": [
+//!     {"name": "app.up", "status": 0, "tags": {}, "timestamp": 1500000000}
+//!   ]
+//! }
+//! ```
+//!
+//! - `type` is one of `count`, `measure`, `sample` or `set`. Measures are
+//!   sent as their summaries (`name.min`, `name.95percentile`, etc.) and sets
+//!   as their number of unique values.
+//! - Timestamps are Unix timestamps in seconds: when the metrics were
+//!   flushed, or when an event happened or a check ran if that's known.
+//! - `source` is left out when a dimension doesn't have one.
+//! - Events may also have `hostname`, `aggregation_key`, `priority`,
+//!   `source_type_name` and `alert_type`; service checks may also have
+//!   `hostname` and `message`. Status is `0` (ok), `1` (warning), `2`
+//!   (critical) or `3` (unknown).
+
+use std::collections::BTreeMap;
+
+use chrono::UTC;
+use hyper::header::CONTENT_TYPE;
+use reqwest::blocking::Client;
+use rustc_serialize::json::{self, Json, ToJson};
+
+use super::Forwarder;
+use super::super::metrics::{AggregatedMetricType, AggregatedMetrics, Dimension, Event, ServiceCheck};
+
+/// Forwards metrics to any HTTP endpoint as JSON (see the module docs for
+/// the schema). Everything flushed together is sent in one request.
+pub struct HttpJsonForwarder {
+    pub url: String,
+    /// Extra headers to send with every request, eg. `Authorization`.
+    pub headers: Vec<(String, String)>,
+}
+
+impl HttpJsonForwarder {
+    pub fn new(url: &str) -> HttpJsonForwarder {
+        HttpJsonForwarder {
+            url: url.to_owned(),
+            headers: vec![],
+        }
+    }
+
+    fn serialize(metrics: &AggregatedMetrics, timestamp: i64) -> Json {
+        let series: Vec<Json> = metrics.iter()
+            .map(|&(ref metric_type, ref dim, value)| {
+                let metric_type = match *metric_type {
+                    AggregatedMetricType::Count   => "count",
+                    AggregatedMetricType::Measure => "measure",
+                    AggregatedMetricType::Sample  => "sample",
+                    AggregatedMetricType::Set     => "set",
+                };
+
+                let mut object = HttpJsonForwarder::serialize_dimension(dim);
+                object.insert("type".to_owned(), metric_type.to_json());
+                object.insert("value".to_owned(), value.to_json());
+                object.insert("timestamp".to_owned(), timestamp.to_json());
+                object.to_json()
+            })
+            .collect();
+
+        let distributions: Vec<Json> = metrics.distributions()
+            .map(|(dim, values)| {
+                let mut object = HttpJsonForwarder::serialize_dimension(dim);
+                object.insert("values".to_owned(), values.to_json());
+                object.insert("timestamp".to_owned(), timestamp.to_json());
+                object.to_json()
+            })
+            .collect();
+
+        let events: Vec<Json> = metrics.events().iter()
+            .map(|event| HttpJsonForwarder::serialize_event(event, timestamp))
+            .collect();
+
+        let service_checks: Vec<Json> = metrics.service_checks().iter()
+            .map(|check| HttpJsonForwarder::serialize_service_check(check, timestamp))
+            .collect();
+
+        let mut data: BTreeMap<String, Json> = BTreeMap::new();
+        data.insert("timestamp".to_owned(), timestamp.to_json());
+        data.insert("metrics".to_owned(), Json::Array(series));
+        data.insert("distributions".to_owned(), Json::Array(distributions));
+        data.insert("events".to_owned(), Json::Array(events));
+        data.insert("service_checks".to_owned(), Json::Array(service_checks));
+        data.to_json()
+    }
+
+    fn serialize_dimension(dim: &Dimension) -> BTreeMap<String, Json> {
+        let mut object: BTreeMap<String, Json> = BTreeMap::new();
+
+        object.insert("name".to_owned(), dim.name.to_json());
+        if let Some(ref source) = dim.source {
+            object.insert("source".to_owned(), source.to_json());
+        }
+        object.insert("tags".to_owned(), dim.tags.to_json());
+
+        object
+    }
+
+    fn serialize_event(event: &Event, timestamp: i64) -> Json {
+        let mut object: BTreeMap<String, Json> = BTreeMap::new();
+
+        object.insert("title".to_owned(), event.title.to_json());
+        object.insert("text".to_owned(), event.text.to_json());
+        object.insert("timestamp".to_owned(), event.timestamp.unwrap_or(timestamp).to_json());
+        object.insert("tags".to_owned(), event.tags.to_json());
+
+        let optional = [
+            ("hostname", &event.hostname),
+            ("aggregation_key", &event.aggregation_key),
+            ("priority", &event.priority),
+            ("source_type_name", &event.source_type_name),
+            ("alert_type", &event.alert_type),
+        ];
+        for &(key, value) in optional.iter() {
+            if let Some(ref value) = *value {
+                object.insert(key.to_owned(), value.to_json());
+            }
+        }
+
+        object.to_json()
+    }
+
+    fn serialize_service_check(check: &ServiceCheck, timestamp: i64) -> Json {
+        let mut object: BTreeMap<String, Json> = BTreeMap::new();
+
+        object.insert("name".to_owned(), check.name.to_json());
+        object.insert("status".to_owned(), (check.status as u8).to_json());
+        object.insert("timestamp".to_owned(), check.timestamp.unwrap_or(timestamp).to_json());
+        object.insert("tags".to_owned(), check.tags.to_json());
+
+        if let Some(ref hostname) = check.hostname {
+            object.insert("hostname".to_owned(), hostname.to_json());
+        }
+        if let Some(ref message) = check.message {
+            object.insert("message".to_owned(), message.to_json());
+        }
+
+        object.to_json()
+    }
+
+    fn send(&self, body: String) {
+        let mut request = Client::new()
+            .post(&self.url)
+            .header(CONTENT_TYPE, "application/json");
+        for (name, value) in self.headers.iter() {
+            request = request.header(name.as_str(), value.as_str());
+        }
+
+        match request.body(body).send() {
+            Err(err) => {
+                println!("HTTP JSON forwarding Error: {:#?}", err)
+            },
+            Ok(res) => {
+                if !res.status().is_success() {
+                    println!("HTTP JSON forwarding Error: {:#?}", res);
+                }
+            },
+        }
+    }
+}
+
+impl Forwarder for HttpJsonForwarder {
+    /// Events and service checks are sent along with the metrics rather
+    /// than separately.
+    fn forward_metrics(&self, metrics: AggregatedMetrics) {
+        let body = json::encode(&HttpJsonForwarder::serialize(&metrics, UTC::now().timestamp())).unwrap();
+        self.send(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use rustc_serialize::json::Json;
+
+    use super::HttpJsonForwarder;
+    use super::super::Forwarder;
+    use super::super::super::metrics::{
+        AggregatedMetrics,
+        AggregatedMetricType,
+        Dimension,
+        Event,
+        ServiceCheck,
+        ServiceCheckStatus,
+    };
+
+    #[test]
+    fn it_serializes_metrics() {
+        let mut metrics = AggregatedMetrics::with_metrics(vec![
+            (AggregatedMetricType::Count, Dimension::with_name_and_source("requests", "web.1").tagged("status", "200"), 42.0),
+            (AggregatedMetricType::Sample, Dimension::with_name("load"), 0.5),
+        ]);
+        metrics.aggregate_distributions(vec![(Dimension::with_name("size"), vec![1.0, 2.0])].into_iter());
+        metrics.add_events(vec![Event::new("Deploy", "v1.2")].into_iter());
+        metrics.add_service_checks(vec![ServiceCheck::new("app.up", ServiceCheckStatus::Ok)].into_iter());
+
+        let json = HttpJsonForwarder::serialize(&metrics, 1500000000);
+
+        let expected = Json::from_str(r#"{
+            "timestamp": 1500000000,
+            "metrics": [
+                {"type": "count", "name": "requests", "source": "web.1", "tags": {"status": "200"}, "value": 42.0, "timestamp": 1500000000},
+                {"type": "sample", "name": "load", "tags": {}, "value": 0.5, "timestamp": 1500000000}
+            ],
+            "distributions": [
+                {"name": "size", "tags": {}, "values": [1.0, 2.0], "timestamp": 1500000000}
+            ],
+            "events": [
+                {"title": "Deploy", "text": "v1.2", "tags": {}, "timestamp": 1500000000}
+            ],
+            "service_checks": [
+                {"name": "app.up", "status": 0, "tags": {}, "timestamp": 1500000000}
+            ]
+        }"#).unwrap();
+        // Compared as text since parsed timestamps are unsigned.
+        assert_eq!(json.to_string(), expected.to_string());
+    }
+
+    #[test]
+    fn it_posts_with_custom_headers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut forwarder = HttpJsonForwarder::new(&format!("http://{}/metrics", listener.local_addr().unwrap()));
+        forwarder.headers.push(("X-Api-Key".to_owned(), "secret".to_owned()));
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+            let mut headers = vec![];
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() { break }
+                headers.push(line.trim().to_lowercase());
+            }
+
+            let length = headers.iter()
+                .find(|header| header.starts_with("content-length:"))
+                .map(|header| header[15..].trim().parse().unwrap())
+                .unwrap();
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+
+            stream.write_all(b"HTTP/1.1 202 Accepted\r\nContent-Length: 0\r\n\r\n").unwrap();
+            (headers, String::from_utf8(body).unwrap())
+        });
+
+        forwarder.forward(AggregatedMetrics::with_metrics(vec![
+            (AggregatedMetricType::Count, Dimension::with_name("requests"), 1.0),
+        ]));
+
+        let (headers, body) = server.join().unwrap();
+        assert!(headers[0].starts_with("post /metrics "));
+        assert!(headers.contains(&"x-api-key: secret".to_owned()));
+        assert!(headers.contains(&"content-type: application/json".to_owned()));
+        assert!(body.contains(r#""name":"requests""#));
+    }
+}
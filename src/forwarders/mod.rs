@@ -1,13 +1,52 @@
 //! Forwarders send aggregated metrics on to other services.
 
+mod connection;
 pub mod datadog;
+pub mod graphite;
+pub mod http_json;
+pub mod prometheus;
+pub mod prometheus_remote_write;
+pub mod statsd;
 
 pub use self::datadog::DatadogForwarder;
+pub use self::graphite::GraphiteForwarder;
+pub use self::http_json::HttpJsonForwarder;
+pub use self::prometheus::PrometheusExporter;
+pub use self::prometheus_remote_write::PrometheusRemoteWriteForwarder;
+pub use self::statsd::StatsdForwarder;
 
-use super::metrics::AggregatedMetrics;
+use super::metrics::{AggregatedMetrics, Event, Metric, ServiceCheck};
 
 /// Handles forwarding on a set of aggregated metrics.
 pub trait Forwarder {
     /// Sends a vector of aggregated metrics to the forwarder's destination.
-    fn forward_metrics(&self, AggregatedMetrics);
+    /// The events and service checks flushed with them aren't sent; use
+    /// `forward` to send those too.
+    fn forward_metrics(&self, metrics: AggregatedMetrics);
+
+    /// Sends events to the forwarder's destination. Forwarders whose
+    /// destination has no notion of events ignore them.
+    fn forward_events(&self, _events: &[Event]) {}
+
+    /// Sends service checks to the forwarder's destination. Like events,
+    /// these are ignored by default.
+    fn forward_service_checks(&self, _service_checks: &[ServiceCheck]) {}
+
+    /// Forwards the events and service checks flushed with the metrics and
+    /// then the metrics themselves.
+    fn forward(&self, metrics: AggregatedMetrics) {
+        self.forward_events(metrics.events());
+        self.forward_service_checks(metrics.service_checks());
+        self.forward_metrics(metrics)
+    }
+}
+
+/// Passes metrics on as they're recorded, before they're aggregated, eg. to
+/// relay them to another distributor. Added to a store with
+/// `SharedStore::add_relay`.
+///
+/// `relay` is called on the thread recording the metrics, so it shouldn't
+/// block on the network; queue the metrics to be sent elsewhere instead.
+pub trait Relay: Send + Sync {
+    fn relay(&self, metrics: &[Metric]);
 }
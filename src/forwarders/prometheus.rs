@@ -0,0 +1,426 @@
+//! Exposes metrics for Prometheus to scrape in its [text exposition
+//! format][].
+//!
+//! [text exposition format]: https://prometheus.io/docs/instrumenting/exposition_formats/
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+use iron::headers::ContentType;
+use iron::middleware::Handler;
+use iron::mime::Mime;
+use iron::prelude::*;
+use iron::status::Status;
+
+use super::Forwarder;
+use super::super::SharedStore;
+use super::super::metrics::{AggregatedMetrics, AggregatedMetricType, Dimension};
+
+/// Quantiles reported for each distribution.
+const QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+/// Where the exporter gets the metrics it serves from.
+enum Source {
+    /// Metrics forwarded to it on each flush, with counts summed across
+    /// flushes so that they can be exposed as counters.
+    Forwarded(Mutex<Forwarded>),
+    /// Whatever has been recorded in the store so far this interval.
+    Live(SharedStore),
+}
+
+#[derive(Default)]
+struct Forwarded {
+    latest: Option<AggregatedMetrics>,
+    counters: Counters,
+}
+
+/// Iron handler serving metrics in the Prometheus text format. Metric names
+/// and tag keys are sanitized to what Prometheus allows (eg. `foo.bar`
+/// becomes `foo_bar`), tags become labels, and a dimension's source becomes
+/// a `source` label.
+///
+/// Created with `new` it's also a `Forwarder`: forward flushed metrics to
+/// it (eg. from `flush_every`) and it serves the latest of them. Counts are
+/// summed across flushes and exposed as `_total` counters; everything else
+/// is a gauge except distributions, which are summaries. Clones share the
+/// same metrics, so one can be given to the router and another used as the
+/// forwarder.
+///
+/// Created with `live` it serves a snapshot of a store's current interval
+/// instead, with counts exposed as gauges since they reset on each flush.
+#[derive(Clone)]
+pub struct PrometheusExporter {
+    /// Number of flushes a counter is kept for without being counted again
+    /// before it's forgotten (and starts over from 0 if it's counted
+    /// later). Defaults to 60 flushes; `None` keeps counters forever.
+    pub counter_expiry: Option<u64>,
+    source: Arc<Source>,
+}
+
+impl PrometheusExporter {
+    pub fn new() -> PrometheusExporter {
+        PrometheusExporter {
+            counter_expiry: Some(60),
+            source: Arc::new(Source::Forwarded(Mutex::new(Forwarded::default()))),
+        }
+    }
+
+    pub fn live(store: SharedStore) -> PrometheusExporter {
+        PrometheusExporter {
+            counter_expiry: Some(60),
+            source: Arc::new(Source::Live(store)),
+        }
+    }
+
+    /// Renders the metrics currently being served.
+    pub fn render(&self) -> String {
+        match *self.source {
+            Source::Forwarded(ref forwarded) => {
+                let forwarded = forwarded.lock().unwrap();
+                let empty = AggregatedMetrics::new();
+                let latest = forwarded.latest.as_ref().unwrap_or(&empty);
+                render(latest, Some(&forwarded.counters))
+            },
+            Source::Live(ref store) => render(&store.snapshot(), None),
+        }
+    }
+}
+
+impl Default for PrometheusExporter {
+    fn default() -> PrometheusExporter {
+        PrometheusExporter::new()
+    }
+}
+
+impl Forwarder for PrometheusExporter {
+    fn forward_metrics(&self, metrics: AggregatedMetrics) {
+        let forwarded = match *self.source {
+            Source::Forwarded(ref forwarded) => forwarded,
+            Source::Live(_) => return,
+        };
+
+        let mut forwarded = forwarded.lock().unwrap();
+        forwarded.counters.add(&metrics, self.counter_expiry);
+        forwarded.latest = Some(metrics);
+    }
+}
+
+impl Handler for PrometheusExporter {
+    fn handle(&self, _: &mut Request) -> IronResult<Response> {
+        let mut response = Response::with((Status::Ok, self.render()));
+        let mime: Mime = "text/plain; version=0.0.4".parse().unwrap();
+        response.headers.set(ContentType(mime));
+        Ok(response)
+    }
+}
+
+/// Single Prometheus sample along with the family it belongs to.
+pub(crate) struct Series {
+    /// Family name; the same as `name` except for summaries' `_sum` and
+    /// `_count` samples.
+    pub family: String,
+    pub metric_type: &'static str,
+    pub name: String,
+    pub labels: BTreeMap<String, String>,
+    pub value: f64,
+}
+
+/// Running totals of the counts in each flush, exposed as `_total`
+/// counters.
+#[derive(Default)]
+pub(crate) struct Counters {
+    /// Total and the flush it was last counted in for each dimension.
+    totals: HashMap<Dimension, (f64, u64)>,
+    flushes: u64,
+}
+
+impl Counters {
+    pub fn new() -> Counters {
+        Counters::default()
+    }
+
+    /// Adds the counts in a flush to the totals, then forgets the counters
+    /// that haven't been counted in the last `expiry` flushes.
+    ///
+    /// Prometheus counters can only go up, so a negative count resets its
+    /// counter to 0 instead of being added.
+    pub fn add(&mut self, metrics: &AggregatedMetrics, expiry: Option<u64>) {
+        self.flushes += 1;
+        let flushes = self.flushes;
+
+        for &(ref metric_type, ref dim, value) in metrics.iter() {
+            if *metric_type != AggregatedMetricType::Count { continue }
+
+            let counter = self.totals.entry(dim.clone()).or_insert((0.0, flushes));
+            counter.0 = if value < 0.0 { 0.0 } else { counter.0 + value };
+            counter.1 = flushes;
+        }
+
+        if let Some(expiry) = expiry {
+            self.totals.retain(|_, &mut (_, counted)| flushes - counted < expiry);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(&Dimension, f64)> {
+        self.totals.iter().map(|(dim, &(total, _))| (dim, total))
+    }
+}
+
+/// Converts aggregated metrics to Prometheus samples. If `counters` is
+/// given then counts are taken from there as `_total` counters rather than
+/// from the metrics as gauges.
+pub(crate) fn collect_series(metrics: &AggregatedMetrics, counters: Option<&Counters>) -> Vec<Series> {
+    let mut collected = vec![];
+
+    for &(ref metric_type, ref dim, value) in metrics.iter() {
+        if *metric_type == AggregatedMetricType::Count && counters.is_some() { continue }
+
+        let name = sanitize_name(&dim.name);
+        collected.push(series(&name, "gauge", name.clone(), dim, None, value));
+    }
+
+    if let Some(counters) = counters {
+        for (dim, value) in counters.iter() {
+            let name = format!("{}_total", sanitize_name(&dim.name));
+            collected.push(series(&name, "counter", name.clone(), dim, None, value));
+        }
+    }
+
+    for (dim, values) in metrics.distributions() {
+        if values.is_empty() { continue }
+
+        let name = sanitize_name(&dim.name);
+        let mut sorted = values.clone();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+
+        for quantile in QUANTILES.iter() {
+            let index = ((sorted.len() as f64 * quantile) as usize).min(sorted.len() - 1);
+            let label = ("quantile", quantile.to_string());
+            collected.push(series(&name, "summary", name.clone(), dim, Some(label), sorted[index]));
+        }
+        let sum = sorted.iter().sum();
+        collected.push(series(&name, "summary", format!("{}_sum", name), dim, None, sum));
+        collected.push(series(&name, "summary", format!("{}_count", name), dim, None, sorted.len() as f64));
+    }
+
+    collected
+}
+
+fn series(family: &str, metric_type: &'static str, name: String, dim: &Dimension,
+          extra: Option<(&str, String)>, value: f64) -> Series {
+    let mut labels = BTreeMap::new();
+    if let Some(ref source) = dim.source {
+        labels.insert("source".to_owned(), source.clone());
+    }
+    for (key, value) in dim.tags.iter() {
+        labels.insert(tag_label(key), value.clone());
+    }
+    if let Some((key, value)) = extra {
+        labels.insert(key.to_owned(), value);
+    }
+
+    Series {
+        family: family.to_owned(),
+        metric_type,
+        name,
+        labels,
+        value,
+    }
+}
+
+/// Renders aggregated metrics grouped into families, each with a `# TYPE`
+/// line.
+fn render(metrics: &AggregatedMetrics, counters: Option<&Counters>) -> String {
+    let mut families: BTreeMap<String, (&'static str, Vec<String>)> = BTreeMap::new();
+
+    for series in collect_series(metrics, counters) {
+        let family = families.entry(series.family.clone()).or_insert_with(|| (series.metric_type, vec![]));
+        if family.0 != series.metric_type {
+            println!("Skipping Prometheus metric {}: already exposed as a {}", series.name, family.0);
+            continue
+        }
+        family.1.push(format_line(&series));
+    }
+
+    let mut output = String::new();
+    for (name, &mut (metric_type, ref mut lines)) in families.iter_mut() {
+        lines.sort();
+        writeln!(output, "# TYPE {} {}", name, metric_type).unwrap();
+        for line in lines.iter() {
+            output.push_str(line);
+            output.push('\n');
+        }
+    }
+    output
+}
+
+fn format_line(series: &Series) -> String {
+    let mut line = series.name.clone();
+    if !series.labels.is_empty() {
+        let labels: Vec<String> = series.labels.iter()
+            .map(|(key, value)| format!("{}=\"{}\"", key, escape_label_value(value)))
+            .collect();
+        write!(line, "{{{}}}", labels.join(",")).unwrap();
+    }
+    write!(line, " {}", format_value(series.value)).unwrap();
+    line
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_owned()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf".to_owned() } else { "-Inf".to_owned() }
+    } else {
+        value.to_string()
+    }
+}
+
+/// Metric names may only contain `[a-zA-Z0-9_:]` and can't start with a
+/// digit.
+fn sanitize_name(name: &str) -> String {
+    sanitize(name, |c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+/// Label names are like metric names but without colons.
+fn sanitize_label(name: &str) -> String {
+    sanitize(name, |c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Tags that would clash with the labels we add, or that use a name
+/// reserved for Prometheus (those starting with `__`, like `__name__`), are
+/// prefixed with `exported_` the way Prometheus renames clashing labels it
+/// scrapes.
+fn tag_label(key: &str) -> String {
+    let label = sanitize_label(key);
+    if label == "source" || label == "quantile" || label.starts_with("__") {
+        format!("exported_{}", label)
+    } else {
+        label
+    }
+}
+
+fn sanitize<F: Fn(char) -> bool>(name: &str, allowed: F) -> String {
+    let mut sanitized: String = name.chars().map(|c| if allowed(c) { c } else { '_' }).collect();
+    if sanitized.chars().next().map_or(true, |c| c.is_ascii_digit()) {
+        sanitized.insert(0, '_');
+    }
+    sanitized
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sanitize_name, tag_label, PrometheusExporter};
+    use super::super::Forwarder;
+    use super::super::super::SharedStore;
+    use super::super::super::metrics::{AggregatedMetrics, AggregatedMetricType, Dimension, Metric};
+
+    #[test]
+    fn it_sanitizes_names() {
+        assert_eq!(sanitize_name("http.requests-2xx"), "http_requests_2xx");
+        assert_eq!(sanitize_name("5xx"), "_5xx");
+        assert_eq!(sanitize_name("ns:metric"), "ns:metric");
+    }
+
+    #[test]
+    fn it_renames_tags_that_clash_with_labels() {
+        assert_eq!(tag_label("http.status"), "http_status");
+        assert_eq!(tag_label("source"), "exported_source");
+        assert_eq!(tag_label("quantile"), "exported_quantile");
+        assert_eq!(tag_label("__name__"), "exported___name__");
+
+        let exporter = PrometheusExporter::new();
+        let dim = Dimension::with_name_and_source("load", "web.1").tagged("source", "a").tagged("__name__", "b");
+        exporter.forward_metrics(AggregatedMetrics::with_metrics(vec![
+            (AggregatedMetricType::Sample, dim, 1.0),
+        ]));
+
+        assert!(exporter.render().contains("load{exported___name__=\"b\",exported_source=\"a\",source=\"web.1\"} 1\n"));
+    }
+
+    #[test]
+    fn it_renders_forwarded_metrics() {
+        let exporter = PrometheusExporter::new();
+        let dim = Dimension::with_name_and_source("web.requests", "web.1").tagged("http.status", "2\"00");
+
+        exporter.forward_metrics(AggregatedMetrics::with_metrics(vec![
+            (AggregatedMetricType::Count, dim.clone(), 2.0),
+            (AggregatedMetricType::Sample, Dimension::with_name("load"), 0.5),
+        ]));
+        exporter.forward_metrics(AggregatedMetrics::with_metrics(vec![
+            (AggregatedMetricType::Count, dim, 3.0),
+        ]));
+
+        assert_eq!(exporter.render(), [
+            "# TYPE web_requests_total counter",
+            "web_requests_total{http_status=\"2\\\"00\",source=\"web.1\"} 5",
+            "",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn it_resets_and_expires_counters() {
+        let mut exporter = PrometheusExporter::new();
+        exporter.counter_expiry = Some(2);
+        let count = |name: &str, value: f64| {
+            AggregatedMetrics::with_metrics(vec![(AggregatedMetricType::Count, Dimension::with_name(name), value)])
+        };
+
+        exporter.forward_metrics(count("jobs", 5.0));
+        exporter.forward_metrics(count("jobs", -2.0));
+        exporter.forward_metrics(count("errors", 1.0));
+        assert_eq!(exporter.render(), [
+            "# TYPE errors_total counter",
+            "errors_total 1",
+            "# TYPE jobs_total counter",
+            "jobs_total 0",
+            "",
+        ].join("\n"));
+
+        exporter.forward_metrics(count("errors", 1.0));
+        assert_eq!(exporter.render(), "# TYPE errors_total counter\nerrors_total 2\n");
+    }
+
+    #[test]
+    fn it_renders_live_snapshots() {
+        let store = SharedStore::new();
+        let exporter = PrometheusExporter::live(store.clone());
+
+        store.record(vec![
+            Metric::Count(Dimension::with_name("jobs"), 2),
+            Metric::Distribution(Dimension::with_name("latency"), 1.0),
+            Metric::Distribution(Dimension::with_name("latency"), 3.0),
+        ]);
+
+        let rendered = exporter.render();
+        assert!(rendered.contains("# TYPE jobs gauge\njobs 2\n"));
+        assert!(rendered.contains("# TYPE latency summary\n"));
+        assert!(rendered.contains("latency{quantile=\"0.5\"} 3\n"));
+        assert!(rendered.contains("latency_sum 4\n"));
+        assert!(rendered.contains("latency_count 2\n"));
+
+        // Scraping doesn't flush the store.
+        assert_eq!(store.flush().len(), 1);
+    }
+
+    #[test]
+    fn it_renders_distributions_containing_nan() {
+        let store = SharedStore::new();
+        let exporter = PrometheusExporter::live(store.clone());
+
+        store.record(vec![
+            Metric::Distribution(Dimension::with_name("latency"), 1.0),
+            Metric::Distribution(Dimension::with_name("latency"), f64::NAN),
+        ]);
+
+        assert!(exporter.render().contains("latency_count 2\n"));
+        // The exporter is still usable afterwards.
+        assert!(exporter.render().contains("latency_count 2\n"));
+    }
+}
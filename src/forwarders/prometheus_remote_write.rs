@@ -0,0 +1,189 @@
+//! Writes metrics to Prometheus-compatible storage (Cortex, Mimir, Thanos
+//! receivers, etc.) using the [remote write protocol][].
+//!
+//! [remote write protocol]: https://prometheus.io/docs/concepts/remote_write_spec/
+
+use std::sync::Mutex;
+
+use chrono::UTC;
+use hyper::header::{CONTENT_ENCODING, CONTENT_TYPE, USER_AGENT};
+use reqwest::blocking::Client;
+use snap::raw::Encoder;
+
+use super::Forwarder;
+use super::prometheus::{collect_series, Counters, Series};
+use super::super::metrics::AggregatedMetrics;
+
+/// Forwards metrics as snappy-compressed remote write protobuf. Metrics are
+/// named and labelled the same way as by `PrometheusExporter`: counts are
+/// summed across flushes into `_total` counters so that `rate()` works on
+/// them, and distributions are sent as summaries.
+pub struct PrometheusRemoteWriteForwarder {
+    /// Remote write endpoint, eg. `http://mimir:8080/api/v1/push`.
+    pub url: String,
+    /// Extra headers to send with every request, eg. `X-Scope-OrgID` to pick
+    /// a Cortex/Mimir tenant or `Authorization`.
+    pub headers: Vec<(String, String)>,
+    /// Number of flushes a counter is kept for without being counted again,
+    /// like `PrometheusExporter::counter_expiry`. Defaults to 60 flushes.
+    pub counter_expiry: Option<u64>,
+    counters: Mutex<Counters>,
+}
+
+impl PrometheusRemoteWriteForwarder {
+    pub fn new(url: &str) -> PrometheusRemoteWriteForwarder {
+        PrometheusRemoteWriteForwarder {
+            url: url.to_owned(),
+            headers: vec![],
+            counter_expiry: Some(60),
+            counters: Mutex::new(Counters::new()),
+        }
+    }
+
+    fn send(&self, body: Vec<u8>) {
+        let mut request = Client::new()
+            .post(&self.url)
+            .header(CONTENT_TYPE, "application/x-protobuf")
+            .header(CONTENT_ENCODING, "snappy")
+            .header(USER_AGENT, "metrics_distributor")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0");
+        for (name, value) in self.headers.iter() {
+            request = request.header(name.as_str(), value.as_str());
+        }
+
+        match request.body(body).send() {
+            Err(err) => {
+                println!("Prometheus remote write HTTP Error: {:#?}", err)
+            },
+            Ok(res) => {
+                if !res.status().is_success() {
+                    println!("Prometheus remote write Error: {:#?}", res);
+                }
+            },
+        }
+    }
+}
+
+impl Forwarder for PrometheusRemoteWriteForwarder {
+    fn forward_metrics(&self, metrics: AggregatedMetrics) {
+        let series = {
+            let mut counters = self.counters.lock().unwrap();
+            counters.add(&metrics, self.counter_expiry);
+            collect_series(&metrics, Some(&counters))
+        };
+        if series.is_empty() { return }
+
+        let timestamp = UTC::now().timestamp() * 1000;
+        let request = encode_write_request(&series, timestamp);
+
+        match Encoder::new().compress_vec(&request) {
+            Ok(body) => self.send(body),
+            Err(err) => println!("Prometheus remote write compression Error: {:?}", err),
+        }
+    }
+}
+
+/// Encodes a `WriteRequest` with one time series per sample, all at the
+/// given timestamp (in milliseconds):
+///
+/// ```text
+/// message WriteRequest { repeated TimeSeries timeseries = 1; }
+/// message TimeSeries { repeated Label labels = 1; repeated Sample samples = 2; }
+/// message Label { string name = 1; string value = 2; }
+/// message Sample { double value = 1; int64 timestamp = 2; }
+/// ```
+fn encode_write_request(series: &[Series], timestamp: i64) -> Vec<u8> {
+    let mut request = vec![];
+
+    for series in series {
+        let mut encoded = vec![];
+
+        // Labels have to be sorted by name, `__name__` included.
+        let mut labels: Vec<(&str, &str)> = series.labels.iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        labels.push(("__name__", &series.name));
+        labels.sort();
+
+        for (name, value) in labels {
+            let mut label = vec![];
+            encode_bytes(&mut label, 1, name.as_bytes());
+            encode_bytes(&mut label, 2, value.as_bytes());
+            encode_bytes(&mut encoded, 1, &label);
+        }
+
+        let mut sample = vec![];
+        encode_key(&mut sample, 1, 1);
+        sample.extend_from_slice(&series.value.to_bits().to_le_bytes());
+        encode_key(&mut sample, 2, 0);
+        encode_varint(&mut sample, timestamp as u64);
+        encode_bytes(&mut encoded, 2, &sample);
+
+        encode_bytes(&mut request, 1, &encoded);
+    }
+
+    request
+}
+
+fn encode_key(buf: &mut Vec<u8>, field: u64, wire_type: u64) {
+    encode_varint(buf, field << 3 | wire_type)
+}
+
+/// Length-delimited field (strings and embedded messages).
+fn encode_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    encode_key(buf, field, 2);
+    encode_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn encode_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{encode_varint, encode_write_request};
+    use super::super::prometheus::Series;
+
+    #[test]
+    fn it_encodes_varints() {
+        let mut buf = vec![];
+        encode_varint(&mut buf, 1);
+        encode_varint(&mut buf, 300);
+        assert_eq!(buf, vec![0x01, 0xac, 0x02]);
+    }
+
+    #[test]
+    fn it_encodes_write_requests() {
+        let mut labels = BTreeMap::new();
+        labels.insert("a".to_owned(), "b".to_owned());
+        let series = Series {
+            family: "up".to_owned(),
+            metric_type: "gauge",
+            name: "up".to_owned(),
+            labels,
+            value: 1.0,
+        };
+
+        let encoded = encode_write_request(&[series], 1000);
+
+        assert_eq!(encoded, vec![
+            0x0a, 0x26,                                     // timeseries
+            0x0a, 0x0e,                                     //   labels
+            0x0a, 0x08, b'_', b'_', b'n', b'a', b'm', b'e', b'_', b'_',
+            0x12, 0x02, b'u', b'p',
+            0x0a, 0x06,                                     //   labels
+            0x0a, 0x01, b'a',
+            0x12, 0x01, b'b',
+            0x12, 0x0c,                                     //   samples
+            0x09, 0, 0, 0, 0, 0, 0, 0xf0, 0x3f,             //     value
+            0x10, 0xe8, 0x07,                               //     timestamp
+        ]);
+    }
+}
@@ -0,0 +1,501 @@
+//! Sends metrics on to another StatsD server (or distributor), either
+//! aggregated or as they're received.
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use super::{Forwarder, Relay};
+use super::connection::PersistentConnection;
+use super::super::collectors::queue::{BoundedQueue, OverflowPolicy};
+use super::super::metrics::{
+    AggregatedMetricType,
+    AggregatedMetrics,
+    Dimension,
+    Event,
+    Metric,
+    ServiceCheck,
+    Tags,
+};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StatsdTransport {
+    /// Lines are packed into datagrams of up to `max_packet_size` bytes.
+    Udp,
+    /// Lines are streamed over a persistent connection.
+    Tcp,
+}
+
+/// Forwards metrics to a StatsD server. It can be used two ways, so that
+/// distributors can be chained into tiers:
+///
+/// - As a `Forwarder` it sends flushed metrics: counts as counters and
+///   everything else (samples, measures' summaries and unique set counts)
+///   as gauges. Distribution points are sent as they are.
+/// - As a `Relay` (see `SharedStore::add_relay`) it sends every metric as
+///   it's recorded, unaggregated, so that the upstream server does the
+///   aggregating. Sample timestamps can't be expressed in StatsD and are
+///   dropped. Relaying happens on the collectors' threads, so relayed
+///   metrics are queued and sent from a background thread instead of
+///   waiting on the upstream server.
+///
+/// Tags and a dimension's source (as a `source` tag) are sent in the
+/// DogStatsD format, as are events and service checks.
+pub struct StatsdForwarder {
+    pub address: String,
+    pub transport: StatsdTransport,
+    /// Largest datagram to send; lines longer than this are sent on their
+    /// own. The default of 1432 bytes fits in a 1500 byte MTU.
+    pub max_packet_size: usize,
+    /// Prepended (with a `.`) to every metric's name.
+    pub prefix: Option<String>,
+    /// How long to wait when connecting and writing over TCP.
+    pub timeout: Duration,
+    /// Number of batches of relayed metrics that can wait to be sent; more
+    /// than that are dropped (eg. while the upstream server is unreachable).
+    /// Defaults to 10,000.
+    pub relay_queue_capacity: usize,
+    udp_socket: Mutex<Option<UdpSocket>>,
+    connection: PersistentConnection,
+    relay_queue: Mutex<Option<Arc<BoundedQueue<Vec<String>>>>>,
+    dropped_relays: AtomicUsize,
+}
+
+impl StatsdForwarder {
+    pub fn new(address: &str, transport: StatsdTransport) -> StatsdForwarder {
+        StatsdForwarder {
+            address: address.to_owned(),
+            transport,
+            max_packet_size: 1432,
+            prefix: None,
+            timeout: Duration::from_secs(5),
+            relay_queue_capacity: 10_000,
+            udp_socket: Mutex::new(None),
+            connection: PersistentConnection::new(),
+            relay_queue: Mutex::new(None),
+            dropped_relays: AtomicUsize::new(0),
+        }
+    }
+
+    /// Number of batches of relayed metrics dropped because the queue was
+    /// full.
+    pub fn dropped_relay_count(&self) -> usize {
+        self.dropped_relays.load(Ordering::Relaxed)
+    }
+
+    /// Queue of lines to relay, starting the thread that sends them the
+    /// first time it's needed. That thread has its own connection to the
+    /// upstream server.
+    fn relay_queue(&self) -> Arc<BoundedQueue<Vec<String>>> {
+        let mut relay_queue = self.relay_queue.lock().unwrap();
+        if let Some(ref queue) = *relay_queue {
+            return queue.clone()
+        }
+
+        let queue = Arc::new(BoundedQueue::new(self.relay_queue_capacity.max(1), OverflowPolicy::DropNewest));
+        let mut sender = StatsdForwarder::new(&self.address, self.transport);
+        sender.max_packet_size = self.max_packet_size;
+        sender.timeout = self.timeout;
+
+        let consumed = queue.clone();
+        thread::spawn(move || {
+            for lines in BoundedQueue::consume(consumed) {
+                sender.send(lines)
+            }
+        });
+
+        *relay_queue = Some(queue.clone());
+        queue
+    }
+
+    fn serialize_aggregated(&self, metrics: &AggregatedMetrics) -> Vec<String> {
+        let mut lines = vec![];
+
+        for &(ref metric_type, ref dim, value) in metrics.iter() {
+            match *metric_type {
+                AggregatedMetricType::Count => lines.push(self.line(dim, value, "c")),
+                _ => self.push_gauge(&mut lines, dim, value),
+            }
+        }
+        for (dim, values) in metrics.distributions() {
+            for value in values {
+                lines.push(self.line(dim, *value, "d"))
+            }
+        }
+
+        lines
+    }
+
+    fn serialize_raw(&self, metrics: &[Metric]) -> Vec<String> {
+        let mut lines = vec![];
+
+        for metric in metrics {
+            match *metric {
+                Metric::Count(ref dim, value) => lines.push(self.line(dim, value, "c")),
+                Metric::SampledCount(ref dim, value, rate) => lines.push(self.line(dim, value, &format!("c|@{}", rate))),
+                Metric::Measure(ref dim, value) => lines.push(self.line(dim, value, "ms")),
+                Metric::SampledMeasure(ref dim, value, rate) => lines.push(self.line(dim, value, &format!("ms|@{}", rate))),
+                Metric::Sample(ref dim, value) |
+                Metric::TimestampedSample(ref dim, value, _) => self.push_gauge(&mut lines, dim, value),
+                Metric::SampleDelta(ref dim, delta) => {
+                    // A signed gauge adjusts the current value.
+                    let delta = if delta < 0.0 { delta.to_string() } else { format!("+{}", delta) };
+                    lines.push(self.line(dim, delta, "g"))
+                },
+                Metric::Set(ref dim, ref value) => lines.push(self.line(dim, sanitize(value), "s")),
+                Metric::Distribution(ref dim, value) => lines.push(self.line(dim, value, "d")),
+            }
+        }
+
+        lines
+    }
+
+    /// Negative gauges would be read as deltas, so they're reset to zero
+    /// first.
+    fn push_gauge(&self, lines: &mut Vec<String>, dim: &Dimension, value: f64) {
+        if value < 0.0 {
+            lines.push(self.line(dim, 0, "g"));
+        }
+        lines.push(self.line(dim, value, "g"))
+    }
+
+    fn line<V: ToString>(&self, dim: &Dimension, value: V, metric_type: &str) -> String {
+        let name = match self.prefix {
+            Some(ref prefix) => format!("{}.{}", prefix, dim.name),
+            None => dim.name.clone(),
+        };
+
+        let mut line = format!("{}:{}|{}", sanitize(&name), value.to_string(), metric_type);
+        let mut tags = dim.tags.clone();
+        if let Some(ref source) = dim.source {
+            tags.insert("source".to_owned(), source.clone());
+        }
+        line.push_str(&serialize_tags(&tags));
+        line
+    }
+
+    fn send(&self, lines: Vec<String>) {
+        if lines.is_empty() { return }
+
+        let result = match self.transport {
+            StatsdTransport::Udp => self.send_udp(&lines),
+            StatsdTransport::Tcp => {
+                let mut data = lines.join("\n");
+                data.push('\n');
+                self.connection.send(&self.address, self.timeout, data.as_bytes())
+            },
+        };
+
+        if let Err(err) = result {
+            println!("StatsD forwarding Error: {:?}", err)
+        }
+    }
+
+    fn send_udp(&self, lines: &[String]) -> io::Result<()> {
+        let mut socket = self.udp_socket.lock().unwrap();
+
+        if socket.is_none() {
+            let addr = self.address.to_socket_addrs()?.next()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no addresses to send to"))?;
+            let local: SocketAddr = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().unwrap();
+
+            let bound = UdpSocket::bind(local)?;
+            bound.connect(addr)?;
+            *socket = Some(bound);
+        }
+
+        let result = pack(lines, self.max_packet_size).iter()
+            .map(|packet| socket.as_ref().unwrap().send(packet.as_bytes()))
+            .find(|sent| sent.is_err())
+            .unwrap_or(Ok(0));
+        if result.is_err() {
+            // Resolve the address again next time in case it's changed.
+            *socket = None;
+        }
+        result.map(|_| ())
+    }
+}
+
+impl Forwarder for StatsdForwarder {
+    fn forward_metrics(&self, metrics: AggregatedMetrics) {
+        self.send(self.serialize_aggregated(&metrics))
+    }
+
+    fn forward_events(&self, events: &[Event]) {
+        self.send(events.iter().map(serialize_event).collect())
+    }
+
+    fn forward_service_checks(&self, service_checks: &[ServiceCheck]) {
+        self.send(service_checks.iter().map(serialize_service_check).collect())
+    }
+}
+
+impl Relay for StatsdForwarder {
+    fn relay(&self, metrics: &[Metric]) {
+        let lines = self.serialize_raw(metrics);
+        if lines.is_empty() { return }
+
+        if !self.relay_queue().push(lines) {
+            self.dropped_relays.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for StatsdForwarder {
+    /// Lets the relay thread send what's left in its queue and exit.
+    fn drop(&mut self) {
+        if let Some(ref queue) = *self.relay_queue.lock().unwrap() {
+            queue.close()
+        }
+    }
+}
+
+/// Joins lines into packets of at most `max_size` bytes.
+fn pack(lines: &[String], max_size: usize) -> Vec<String> {
+    let mut packets: Vec<String> = vec![];
+    let mut packet = String::new();
+
+    for line in lines {
+        if !packet.is_empty() && packet.len() + 1 + line.len() > max_size {
+            packets.push(packet);
+            packet = String::new();
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(line);
+    }
+    if !packet.is_empty() {
+        packets.push(packet);
+    }
+
+    packets
+}
+
+/// Names and values can't contain the characters that delimit a line.
+fn sanitize(name: &str) -> String {
+    name.chars().map(|c| if c == ':' || c == '|' || c == '@' || c == '#' || c.is_whitespace() { '_' } else { c }).collect()
+}
+
+fn sanitize_tag(tag: &str) -> String {
+    tag.chars().map(|c| if c == ',' || c == '|' || c.is_whitespace() { '_' } else { c }).collect()
+}
+
+/// `|#key:value,key` (tags with an empty value are sent as just their key).
+fn serialize_tags(tags: &Tags) -> String {
+    if tags.is_empty() { return String::new() }
+
+    let tags: Vec<String> = tags.iter()
+        .map(|(key, value)| {
+            if value.is_empty() {
+                sanitize_tag(key)
+            } else {
+                format!("{}:{}", sanitize_tag(key), sanitize_tag(value))
+            }
+        })
+        .collect();
+    format!("|#{}", tags.join(","))
+}
+
+/// Newlines in titles, texts and messages are escaped as `\n`.
+fn escape(s: &str) -> String {
+    s.replace('\n', "\\n")
+}
+
+fn serialize_event(event: &Event) -> String {
+    let title = escape(&event.title);
+    let text = escape(&event.text);
+    let mut line = format!("_e{{{},{}}}:{}|{}", title.len(), text.len(), title, text);
+
+    if let Some(timestamp) = event.timestamp {
+        line.push_str(&format!("|d:{}", timestamp));
+    }
+    let fields = [
+        ("h", &event.hostname),
+        ("k", &event.aggregation_key),
+        ("p", &event.priority),
+        ("s", &event.source_type_name),
+        ("t", &event.alert_type),
+    ];
+    for &(key, value) in fields.iter() {
+        if let Some(ref value) = *value {
+            line.push_str(&format!("|{}:{}", key, value.replace('|', "_")));
+        }
+    }
+    line.push_str(&serialize_tags(&event.tags));
+
+    line
+}
+
+fn serialize_service_check(check: &ServiceCheck) -> String {
+    let mut line = format!("_sc|{}|{}", sanitize_tag(&check.name), check.status as u8);
+
+    if let Some(timestamp) = check.timestamp {
+        line.push_str(&format!("|d:{}", timestamp));
+    }
+    if let Some(ref hostname) = check.hostname {
+        line.push_str(&format!("|h:{}", hostname.replace('|', "_")));
+    }
+    line.push_str(&serialize_tags(&check.tags));
+    // The message has to come last.
+    if let Some(ref message) = check.message {
+        line.push_str(&format!("|m:{}", escape(message)));
+    }
+
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use socket2::{Domain, Socket, Type};
+
+    use super::{pack, serialize_event, serialize_service_check, StatsdForwarder, StatsdTransport};
+    use super::super::{Forwarder, Relay};
+    use super::super::super::SharedStore;
+    use super::super::super::metrics::{
+        AggregatedMetrics,
+        AggregatedMetricType,
+        Dimension,
+        Event,
+        Metric,
+        ServiceCheck,
+        ServiceCheckStatus,
+    };
+    use super::super::super::parsers::statsd::parse_metrics;
+
+    #[test]
+    fn it_serializes_aggregated_metrics() {
+        let mut forwarder = StatsdForwarder::new("localhost:8125", StatsdTransport::Udp);
+        forwarder.prefix = Some("tier1".to_owned());
+
+        let metrics = AggregatedMetrics::with_metrics(vec![
+            (AggregatedMetricType::Count, Dimension::with_name_and_source("requests", "web1"), 3.0),
+            (AggregatedMetricType::Sample, Dimension::with_name("temp").tagged("room", "a"), -2.5),
+        ]);
+
+        assert_eq!(forwarder.serialize_aggregated(&metrics), vec![
+            "tier1.requests:3|c|#source:web1",
+            "tier1.temp:0|g|#room:a",
+            "tier1.temp:-2.5|g|#room:a",
+        ]);
+    }
+
+    #[test]
+    fn raw_metrics_round_trip() {
+        let forwarder = StatsdForwarder::new("localhost:8125", StatsdTransport::Udp);
+        let metrics = vec![
+            Metric::SampledCount(Dimension::with_name("requests").tagged("beta", ""), 1, 0.5),
+            Metric::SampledMeasure(Dimension::with_name("latency"), 12.5, 0.1),
+            Metric::Sample(Dimension::with_name("load"), 0.75),
+            Metric::SampleDelta(Dimension::with_name("queue"), -2.0),
+            Metric::Set(Dimension::with_name("users"), "bob".to_owned()),
+            Metric::Distribution(Dimension::with_name("size"), 512.0),
+        ];
+
+        let message = forwarder.serialize_raw(&metrics).join("\n");
+        let parsed = parse_metrics(message.as_bytes());
+
+        assert_eq!(parsed.errors, vec![]);
+        let reparsed: Vec<Metric> = parsed.metrics.iter().map(|m| m.to_standard_metric()).collect();
+        assert_eq!(reparsed, metrics);
+    }
+
+    #[test]
+    fn events_and_service_checks_round_trip() {
+        let mut event = Event::new("Deploy", "v1.2\nby bob");
+        event.timestamp = Some(1500000000);
+        event.alert_type = Some("info".to_owned());
+        event.tags.insert("env".to_owned(), "prod".to_owned());
+
+        let mut check = ServiceCheck::new("app.up", ServiceCheckStatus::Critical);
+        check.hostname = Some("web1".to_owned());
+        check.message = Some("down | out".to_owned());
+
+        let message = format!("{}\n{}", serialize_event(&event), serialize_service_check(&check));
+        let parsed = parse_metrics(message.as_bytes());
+
+        assert_eq!(parsed.events, vec![event]);
+        assert_eq!(parsed.service_checks, vec![check]);
+    }
+
+    #[test]
+    fn it_packs_lines_into_packets() {
+        let lines: Vec<String> = vec!["a:1|c".to_owned(), "b:1|c".to_owned(), "c:1|c".to_owned()];
+
+        assert_eq!(pack(&lines, 11), vec!["a:1|c\nb:1|c", "c:1|c"]);
+        assert_eq!(pack(&lines, 3), vec!["a:1|c", "b:1|c", "c:1|c"]);
+    }
+
+    #[test]
+    fn it_relays_recorded_metrics_over_udp() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let forwarder = StatsdForwarder::new(&server.local_addr().unwrap().to_string(), StatsdTransport::Udp);
+
+        let store = SharedStore::new();
+        store.add_relay(Arc::new(forwarder));
+        store.record(vec![
+            Metric::Count(Dimension::with_name("foo"), 1),
+            Metric::Count(Dimension::with_name("bar"), 2),
+        ]);
+
+        let mut buf = [0; 1500];
+        let size = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..size], b"foo:1|c\nbar:2|c");
+    }
+
+    #[test]
+    fn it_forwards_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let forwarder = StatsdForwarder::new(&listener.local_addr().unwrap().to_string(), StatsdTransport::Tcp);
+
+        forwarder.forward_metrics(AggregatedMetrics::with_metrics(vec![
+            (AggregatedMetricType::Count, Dimension::with_name("foo"), 2.0),
+        ]));
+        forwarder.relay(&[Metric::Count(Dimension::with_name("bar"), 1)]);
+
+        // Relayed metrics are sent over a connection of their own.
+        let lines: Vec<String> = (0..2)
+            .map(|_| BufReader::new(listener.accept().unwrap().0).lines().next().unwrap().unwrap())
+            .collect();
+        assert_eq!(lines, vec!["foo:2|c", "bar:1|c"]);
+    }
+
+    #[test]
+    fn relaying_doesnt_wait_for_the_upstream_server() {
+        // A server that never accepts, with its backlog filled up so that
+        // connecting to it hangs.
+        let server = Socket::new(Domain::ipv4(), Type::stream(), None).unwrap();
+        server.bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap().into()).unwrap();
+        server.listen(0).unwrap();
+        let addr = server.local_addr().unwrap().as_std().unwrap();
+        let _clients: Vec<TcpStream> = (0..10)
+            .map(|_| TcpStream::connect_timeout(&addr, Duration::from_millis(100)))
+            .take_while(|client| client.is_ok())
+            .map(|client| client.unwrap())
+            .collect();
+
+        let mut forwarder = StatsdForwarder::new(&addr.to_string(), StatsdTransport::Tcp);
+        forwarder.relay_queue_capacity = 1;
+        let forwarder = Arc::new(forwarder);
+
+        let store = SharedStore::new();
+        store.add_relay(forwarder.clone());
+        let started = Instant::now();
+        for _ in 0..3 {
+            store.record(vec![Metric::Count(Dimension::with_name("foo"), 1)]);
+        }
+
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(forwarder.dropped_relay_count() >= 1);
+        assert_eq!(store.flush().len(), 1);
+    }
+}
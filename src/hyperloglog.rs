@@ -0,0 +1,95 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Range of precisions a `HyperLogLog` supports.
+pub const PRECISIONS: std::ops::RangeInclusive<u8> = 4..=16;
+
+/// Estimates the number of distinct values it has seen in a fixed amount of
+/// memory (`2^precision` bytes).
+///
+/// See the [paper][] for details of the algorithm.
+///
+/// [paper]: http://algo.inria.fr/flajolet/Publications/FlFuGaMe07.pdf
+#[derive(Clone, Debug, PartialEq)]
+pub struct HyperLogLog {
+    precision: u8,
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    /// Precision should be one of `PRECISIONS` (4 to 16) and is clamped to
+    /// that range otherwise; the standard error of the estimate is roughly
+    /// `1.04 / sqrt(2^precision)`.
+    pub fn new(precision: u8) -> HyperLogLog {
+        let precision = precision.clamp(*PRECISIONS.start(), *PRECISIONS.end());
+
+        HyperLogLog {
+            precision,
+            registers: vec![0; 1 << precision],
+        }
+    }
+
+    pub fn insert<T: Hash + ?Sized>(&mut self, value: &T) {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        // The first `precision` bits pick the register, the position of the
+        // first set bit in the rest is the register's candidate value.
+        let index = (hash >> (64 - self.precision)) as usize;
+        let rank = ((hash << self.precision).leading_zeros() + 1).min(64 - self.precision as u32 + 1) as u8;
+
+        if rank > self.registers[index] {
+            self.registers[index] = rank
+        }
+    }
+
+    pub fn count(&self) -> f64 {
+        let m = self.registers.len() as f64;
+
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _  => 0.7213 / (1.0 + 1.079 / m),
+        };
+
+        let sum: f64 = self.registers.iter().map(|&r| 2f64.powi(-(r as i32))).sum();
+        let estimate = alpha * m * m / sum;
+
+        // Use linear counting for small cardinalities where the raw estimate
+        // is biased.
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            m * (m / zeros as f64).ln()
+        } else {
+            estimate
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HyperLogLog;
+
+    #[test]
+    fn it_counts_small_sets_exactly() {
+        let mut hll = HyperLogLog::new(12);
+        for value in &["a", "b", "c", "a", "b"] {
+            hll.insert(*value);
+        }
+
+        assert_eq!(hll.count().round(), 3.0)
+    }
+
+    #[test]
+    fn it_estimates_large_sets() {
+        let mut hll = HyperLogLog::new(12);
+        for value in 0..100000 {
+            hll.insert(&value.to_string());
+        }
+
+        let error = (hll.count() - 100000.0).abs() / 100000.0;
+        assert!(error < 0.05, "error too large: {}", error)
+    }
+}
@@ -1,3 +1,7 @@
+// `Option::is_none_or` and `is_some_and` are newer than the Rust versions
+// this crate supports, so `map_or` is used instead.
+#![allow(clippy::unnecessary_map_or)]
+
 #[macro_use]
 extern crate lazy_static;
 #[macro_use]
@@ -9,11 +13,18 @@ extern crate iron;
 extern crate reqwest;
 extern crate regex;
 extern crate rustc_serialize;
+extern crate snap;
+extern crate socket2;
+extern crate url;
+#[cfg(feature = "tokio")]
+extern crate tokio;
 
 pub mod collectors;
 pub mod forwarders;
 pub mod parsers;
 
+mod hyperloglog;
+
 /// Types representing collected and aggregated metrics.
 pub mod metrics;
 /// Stores actually record collected metrics.
@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::slice::Iter;
 use std::cmp::Ordering::Equal;
 
@@ -8,17 +9,42 @@ pub use self::Metric::*;
 /// Metric collected from a collector to be recorded in a store.
 #[derive(Clone, Debug, PartialEq)]
 pub enum Metric {
-    Count(Dimension, u64),
+    Count(Dimension, i64),
     Measure(Dimension, f64),
     Sample(Dimension, f64),
+    /// Adjustment to the last known value of a sample (eg. `+1` or `-2.5`).
+    /// See `BaseStore::sample_expiry` for how long that value is known.
+    SampleDelta(Dimension, f64),
+    /// Sample taken at the given Unix timestamp (in seconds). Within a flush
+    /// interval the sample with the newest timestamp wins, even if older
+    /// samples arrive after it.
+    TimestampedSample(Dimension, f64, i64),
+    /// Count that the client only sends a fraction of the time. The last
+    /// value is that fraction (the sample rate), so a count of 1 at a rate
+    /// of 0.1 is recorded as a count of 10.
+    SampledCount(Dimension, i64, f64),
+    /// Measure that the client only sends a fraction of the time. The value
+    /// itself is recorded as-is but counts for `1 / rate` measures.
+    SampledMeasure(Dimension, f64, f64),
+    /// Occurrence of a value; sets are aggregated into the number of unique
+    /// values seen.
+    Set(Dimension, String),
+    /// Point in a distribution. Distributions aren't aggregated; all of
+    /// their points are passed on to forwarders so that percentiles can be
+    /// computed across all the nodes reporting them.
+    Distribution(Dimension, f64),
 }
 
-/// Metrics can grouped by multiple values. Right now that limited to just
-/// their name.
+/// Key/value tags attached to a dimension, sorted by key.
+pub type Tags = BTreeMap<String, String>;
+
+/// Metrics can grouped by multiple values: their name, an optional source,
+/// and any tags.
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub struct Dimension {
     pub name: String,
     pub source: Option<String>,
+    pub tags: Tags,
 }
 
 impl Dimension {
@@ -26,6 +52,7 @@ impl Dimension {
         Dimension {
             name: name.as_ref().to_owned(),
             source: None,
+            tags: Tags::new(),
         }
     }
 
@@ -33,15 +60,40 @@ impl Dimension {
         Dimension {
             name: name.as_ref().to_owned(),
             source: Some(source.as_ref().to_owned()),
+            tags: Tags::new(),
         }
     }
 
+    /// Sets the dimension's source.
+    pub fn sourced<S: AsRef<str>>(mut self, source: S) -> Dimension {
+        self.source = Some(source.as_ref().to_owned());
+        self
+    }
+
+    /// Adds a tag to the dimension, replacing any existing tag with the
+    /// same key.
+    pub fn tagged<K: AsRef<str>, V: AsRef<str>>(mut self, key: K, value: V) -> Dimension {
+        self.tags.insert(key.as_ref().to_owned(), value.as_ref().to_owned());
+        self
+    }
+
+    /// Adds all the given tags to the dimension.
+    pub fn with_tags<I, K, V>(mut self, tags: I) -> Dimension
+        where I: IntoIterator<Item=(K, V)>, K: AsRef<str>, V: AsRef<str>
+    {
+        for (key, value) in tags {
+            self.tags.insert(key.as_ref().to_owned(), value.as_ref().to_owned());
+        }
+        self
+    }
+
     /// Returns a new `Dimension` with the given name; all other fields will
     /// be copied over.
     pub fn renamed<S: AsRef<str>>(&self, name: S) -> Dimension {
         Dimension {
             name: name.as_ref().to_owned(),
             source: self.source.clone(),
+            tags: self.tags.clone(),
         }
     }
 }
@@ -51,49 +103,59 @@ pub enum AggregatedMetricType {
     Count,
     Measure,
     Sample,
+    Set,
 }
 
 /// The final value resulting from aggregating a metric's values.
 pub type AggregatedMetric = (AggregatedMetricType, Dimension, f64);
 
+/// All the points recorded for a distribution in a time interval.
+pub type DistributionPoints = (Dimension, Vec<f64>);
+
 /// All the metrics in a given time interval coalesced into a single value for
-/// each metric.
+/// each metric. Distributions are the exception: their raw points are kept.
+/// Events and service checks received in the interval are included too.
 #[derive(Debug, PartialEq)]
 pub struct AggregatedMetrics {
     metrics: Vec<AggregatedMetric>,
+    distributions: Vec<DistributionPoints>,
+    events: Vec<Event>,
+    service_checks: Vec<ServiceCheck>,
 }
 
 impl AggregatedMetrics {
     pub fn new() -> AggregatedMetrics {
-        AggregatedMetrics {
-            metrics: vec![],
-        }
+        AggregatedMetrics::with_metrics(vec![])
     }
 
     pub fn with_metrics(metrics: Vec<AggregatedMetric>) -> AggregatedMetrics {
         AggregatedMetrics {
-            metrics: metrics,
+            metrics,
+            distributions: vec![],
+            events: vec![],
+            service_checks: vec![],
         }
     }
 
     pub fn aggregate_counts<'a, I>(&mut self, counts: I)
-        where I: Iterator<Item=(&'a Dimension, &'a u64)>
+        where I: Iterator<Item=(&'a Dimension, &'a f64)>
     {
         for (dim, value) in counts {
-            self.metrics.push((AggregatedMetricType::Count, dim.to_owned(), *value as f64))
+            self.metrics.push((AggregatedMetricType::Count, dim.to_owned(), *value))
         }
     }
 
     /// Rolls up all the given measures. The minimum, maximum, median,
     /// average (mean), and 95th percentile summary measures will all be
     /// emitted, as well as a total count of all the individual measures
-    /// received in the period.
+    /// received in the period. That count is the third item of each
+    /// measure so that sampled measures can be weighted accordingly.
     pub fn aggregate_measures<'a, I>(&mut self, measures: I)
-        where I: Iterator<Item=(&'a Dimension, &'a Vec<f64>)>
+        where I: Iterator<Item=(&'a Dimension, &'a Vec<f64>, f64)>
     {
         use self::AggregatedMetricType::*;
 
-        for (dim, values) in measures {
+        for (dim, values, count) in measures {
             let mut sorted = values.clone();
             sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Equal));
 
@@ -111,7 +173,7 @@ impl AggregatedMetrics {
             self.metrics.push((Measure, dim.renamed(format!("{}.95percentile", dim.name)), percentile95));
             self.metrics.push((Measure, dim.renamed(format!("{}.99percentile", dim.name)), percentile99));
 
-            self.metrics.push((Count,   dim.renamed(format!("{}.count", dim.name)), sorted.len() as f64));
+            self.metrics.push((Count,   dim.renamed(format!("{}.count", dim.name)), count));
         }
     }
 
@@ -123,7 +185,47 @@ impl AggregatedMetrics {
         }
     }
 
-    pub fn iter(&self) -> Iter<AggregatedMetric> {
+    /// Adds the number of unique values seen for each set.
+    pub fn aggregate_sets<I>(&mut self, sets: I)
+        where I: Iterator<Item=(Dimension, f64)>
+    {
+        for (dim, count) in sets {
+            self.metrics.push((AggregatedMetricType::Set, dim, count))
+        }
+    }
+
+    /// Adds the points of each distribution as they are.
+    pub fn aggregate_distributions<I>(&mut self, distributions: I)
+        where I: Iterator<Item=DistributionPoints>
+    {
+        self.distributions.extend(distributions)
+    }
+
+    pub fn distributions(&self) -> Iter<'_, DistributionPoints> {
+        self.distributions.iter()
+    }
+
+    pub fn add_events<I>(&mut self, events: I)
+        where I: Iterator<Item=Event>
+    {
+        self.events.extend(events)
+    }
+
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    pub fn add_service_checks<I>(&mut self, service_checks: I)
+        where I: Iterator<Item=ServiceCheck>
+    {
+        self.service_checks.extend(service_checks)
+    }
+
+    pub fn service_checks(&self) -> &[ServiceCheck] {
+        &self.service_checks
+    }
+
+    pub fn iter(&self) -> Iter<'_, AggregatedMetric> {
         self.metrics.iter()
     }
 
@@ -131,3 +233,72 @@ impl AggregatedMetrics {
         self.metrics.len()
     }
 }
+
+/// Event (eg. a deployment) received from a collector. Events aren't
+/// aggregated; every event recorded in an interval is passed on to
+/// forwarders.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    pub title: String,
+    pub text: String,
+    /// Unix timestamp (in seconds) of when the event happened.
+    pub timestamp: Option<i64>,
+    pub hostname: Option<String>,
+    pub aggregation_key: Option<String>,
+    /// Either `normal` or `low`.
+    pub priority: Option<String>,
+    pub source_type_name: Option<String>,
+    /// One of `error`, `warning`, `info`, or `success`.
+    pub alert_type: Option<String>,
+    pub tags: Tags,
+}
+
+impl Event {
+    pub fn new<T: AsRef<str>, S: AsRef<str>>(title: T, text: S) -> Event {
+        Event {
+            title: title.as_ref().to_owned(),
+            text: text.as_ref().to_owned(),
+            timestamp: None,
+            hostname: None,
+            aggregation_key: None,
+            priority: None,
+            source_type_name: None,
+            alert_type: None,
+            tags: Tags::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ServiceCheckStatus {
+    Ok = 0,
+    Warning = 1,
+    Critical = 2,
+    Unknown = 3,
+}
+
+/// Result of checking the health of a service. Like events, these are passed
+/// on to forwarders as they are.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServiceCheck {
+    pub name: String,
+    pub status: ServiceCheckStatus,
+    /// Unix timestamp (in seconds) of when the check ran.
+    pub timestamp: Option<i64>,
+    pub hostname: Option<String>,
+    pub message: Option<String>,
+    pub tags: Tags,
+}
+
+impl ServiceCheck {
+    pub fn new<S: AsRef<str>>(name: S, status: ServiceCheckStatus) -> ServiceCheck {
+        ServiceCheck {
+            name: name.as_ref().to_owned(),
+            status,
+            timestamp: None,
+            hostname: None,
+            message: None,
+            tags: Tags::new(),
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! Parses the [DogStatsD][] event and service check datagrams:
+//!
+//! - Events: `_e{title.length,text.length}:title|text|d:timestamp|h:hostname|#tag1,tag2`
+//! - Service checks: `_sc|name|status|d:timestamp|h:hostname|#tag1,tag2|m:message`
+//!
+//! [DogStatsD]: https://docs.datadoghq.com/developers/dogstatsd/datagram_shell/
+
+use std::str::{self, FromStr};
+
+use nom::{digit, IResult};
+
+use super::super::metrics::{Event, ServiceCheck, ServiceCheckStatus, Tags};
+
+pub fn parse_event(i: &[u8]) -> IResult<&[u8], Event> {
+    do_parse!(i,
+        tag!("_e{") >>
+        title_length: parse_length >> tag!(",") >>
+        text_length: parse_length  >> tag!("}:") >>
+        title: take_str!(title_length) >> tag!("|") >>
+        text: take_str!(text_length) >>
+        fields: parse_fields >>
+        event: expr_res!(build_event(title, text, fields)) >>
+        (event)
+    )
+}
+
+pub fn parse_service_check(i: &[u8]) -> IResult<&[u8], ServiceCheck> {
+    do_parse!(i,
+        tag!("_sc|") >>
+        name: map_res!(take_while1!(is_field_char), str::from_utf8) >> tag!("|") >>
+        status: parse_status >>
+        fields: parse_fields >>
+        check: expr_res!(build_service_check(name, status, fields)) >>
+        (check)
+    )
+}
+
+fn parse_length(i: &[u8]) -> IResult<&[u8], usize> {
+    map_res!(i,
+        map_res!(digit, str::from_utf8),
+        usize::from_str
+    )
+}
+
+fn parse_status(i: &[u8]) -> IResult<&[u8], ServiceCheckStatus> {
+    alt!(i,
+        value!(ServiceCheckStatus::Ok, tag!("0")) |
+        value!(ServiceCheckStatus::Warning, tag!("1")) |
+        value!(ServiceCheckStatus::Critical, tag!("2")) |
+        value!(ServiceCheckStatus::Unknown, tag!("3"))
+    )
+}
+
+#[inline]
+fn is_field_char(i: u8) -> bool {
+    i != b'|' && i != b'\n'
+}
+
+/// Parses the `|`-prefixed metadata fields following an event or check.
+fn parse_fields(i: &[u8]) -> IResult<&[u8], Vec<&str>> {
+    many0!(i,
+        preceded!(
+            tag!("|"),
+            map_res!(take_while!(is_field_char), str::from_utf8)
+        )
+    )
+}
+
+/// Newlines in titles, texts and messages are escaped as `\n`.
+fn unescape(s: &str) -> String {
+    s.replace("\\n", "\n")
+}
+
+fn parse_tags(tags: &str) -> Tags {
+    tags.split(',')
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| {
+            let mut parts = tag.splitn(2, ':');
+            let key = parts.next().unwrap_or("").to_owned();
+            let value = parts.next().unwrap_or("").to_owned();
+            (key, value)
+        })
+        .collect()
+}
+
+fn parse_timestamp(timestamp: &str) -> Result<i64, String> {
+    i64::from_str(timestamp).map_err(|_| format!("Invalid timestamp: {:?}", timestamp))
+}
+
+fn build_event(title: &str, text: &str, fields: Vec<&str>) -> Result<Event, String> {
+    let mut event = Event::new(unescape(title), unescape(text));
+
+    for field in fields {
+        if let Some(tags) = field.strip_prefix('#') {
+            event.tags = parse_tags(tags);
+            continue
+        }
+
+        let (key, value) = match field.find(':') {
+            Some(index) => (&field[..index], &field[index + 1..]),
+            None => return Err(format!("Invalid event field: {:?}", field)),
+        };
+
+        match key {
+            "d" => event.timestamp = Some(parse_timestamp(value)?),
+            "h" => event.hostname = Some(value.to_owned()),
+            "k" => event.aggregation_key = Some(value.to_owned()),
+            "p" => event.priority = Some(value.to_owned()),
+            "s" => event.source_type_name = Some(value.to_owned()),
+            "t" => event.alert_type = Some(value.to_owned()),
+            _   => return Err(format!("Unknown event field: {:?}", field)),
+        }
+    }
+
+    Ok(event)
+}
+
+fn build_service_check(name: &str, status: ServiceCheckStatus, fields: Vec<&str>) -> Result<ServiceCheck, String> {
+    let mut check = ServiceCheck::new(name, status);
+
+    for (index, field) in fields.iter().enumerate() {
+        if let Some(tags) = field.strip_prefix('#') {
+            check.tags = parse_tags(tags);
+        } else if let Some(timestamp) = field.strip_prefix("d:") {
+            check.timestamp = Some(parse_timestamp(timestamp)?);
+        } else if let Some(hostname) = field.strip_prefix("h:") {
+            check.hostname = Some(hostname.to_owned());
+        } else if let Some(message) = field.strip_prefix("m:") {
+            // The message is always last and may itself contain pipes.
+            let mut message = vec![message];
+            message.extend_from_slice(&fields[index + 1..]);
+            check.message = Some(unescape(&message.join("|")));
+            break
+        } else {
+            return Err(format!("Unknown service check field: {:?}", field))
+        }
+    }
+
+    Ok(check)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nom::IResult;
+    use super::super::super::metrics::*;
+
+    #[test]
+    fn it_parses_event() {
+        let mut expected = Event::new("Deploy", "Deployed\nv1.2");
+        expected.timestamp = Some(1500000000);
+        expected.hostname = Some("web.1".to_owned());
+        expected.alert_type = Some("success".to_owned());
+        expected.tags.insert("env".to_owned(), "prod".to_owned());
+
+        assert_eq!(
+            parse_event(&b"_e{6,14}:Deploy|Deployed\\nv1.2|d:1500000000|h:web.1|t:success|#env:prod"[..]),
+            IResult::Done(&b""[..], expected)
+        )
+    }
+
+    #[test]
+    fn it_parses_event_without_fields() {
+        assert_eq!(
+            parse_event(&b"_e{1,2}:a|bc"[..]),
+            IResult::Done(&b""[..], Event::new("a", "bc"))
+        )
+    }
+
+    #[test]
+    fn it_rejects_event_with_wrong_lengths() {
+        assert!(!parse_event(&b"_e{5,2}:a|bc"[..]).is_done());
+        assert!(parse_event(&b"_e{1,2}:a|bc|d:soon"[..]).is_err());
+    }
+
+    #[test]
+    fn it_parses_service_check() {
+        let mut expected = ServiceCheck::new("app.health", ServiceCheckStatus::Critical);
+        expected.hostname = Some("web.1".to_owned());
+        expected.message = Some("down | again".to_owned());
+        expected.tags.insert("env".to_owned(), "prod".to_owned());
+
+        assert_eq!(
+            parse_service_check(&b"_sc|app.health|2|h:web.1|#env:prod|m:down | again"[..]),
+            IResult::Done(&b""[..], expected)
+        )
+    }
+
+    #[test]
+    fn it_rejects_service_check_with_invalid_status() {
+        assert!(parse_service_check(&b"_sc|app.health|7"[..]).is_err());
+    }
+}
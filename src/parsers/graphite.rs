@@ -0,0 +1,196 @@
+//! Parses the [Graphite plaintext protocol][]: one metric per line in the
+//! form `path value timestamp`. Tags can be added to the path the way
+//! Graphite 1.1 does: `disk.used;host=web.1;mount=/ 42 1500000000`.
+//!
+//! [Graphite plaintext protocol]: https://graphite.readthedocs.io/en/latest/feeding-carbon.html#the-plaintext-protocol
+
+use std::str::FromStr;
+
+use super::ParseError;
+use super::super::metrics::{Dimension, Metric};
+
+/// Metric parsed from a line of Graphite plaintext. Graphite values are
+/// the value of the series at that moment, so they become samples.
+#[derive(Debug, PartialEq)]
+pub struct GraphiteMetric {
+    pub dimension: Dimension,
+    pub value: f64,
+    /// Unix timestamp (in seconds) the value is for; `None` if the client
+    /// left it out or sent `-1` to mean "now".
+    pub timestamp: Option<i64>,
+}
+
+impl GraphiteMetric {
+    pub fn to_standard_metric(&self) -> Metric {
+        match self.timestamp {
+            Some(timestamp) => Metric::TimestampedSample(self.dimension.clone(), self.value, timestamp),
+            None            => Metric::Sample(self.dimension.clone(), self.value),
+        }
+    }
+}
+
+/// Metrics parsed from a Graphite message along with errors for any lines
+/// that couldn't be parsed.
+#[derive(Debug, PartialEq)]
+pub struct ParsedMetrics {
+    pub metrics: Vec<GraphiteMetric>,
+    pub errors: Vec<ParseError>,
+}
+
+/// Parses a newline-separated Graphite message. Like with StatsD a malformed
+/// line doesn't prevent the rest of the message from being parsed, and
+/// blank lines are skipped.
+pub fn parse_metrics(message: &str) -> ParsedMetrics {
+    let mut parsed = ParsedMetrics {
+        metrics: vec![],
+        errors: vec![],
+    };
+
+    let mut offset = 0;
+    for line in message.split('\n') {
+        let line_offset = offset;
+        offset += line.len() + 1;
+
+        if line.trim().is_empty() { continue }
+
+        match parse_line(line) {
+            Ok(metric) => parsed.metrics.push(metric),
+            Err(description) => {
+                parsed.errors.push(ParseError {
+                    line: line.to_owned(),
+                    offset: line_offset,
+                    description,
+                })
+            },
+        }
+    }
+
+    parsed
+}
+
+/// Parses a single `path value timestamp` line.
+pub fn parse_line(line: &str) -> Result<GraphiteMetric, String> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+
+    let (path, value, timestamp) = match fields.len() {
+        2 => (fields[0], fields[1], None),
+        3 => (fields[0], fields[1], Some(fields[2])),
+        _ => return Err(format!("Expected `path value timestamp`, got {} fields", fields.len())),
+    };
+
+    let dimension = parse_path(path)?;
+
+    let value = match f64::from_str(value) {
+        Ok(value) if value.is_finite() => value,
+        _ => return Err(format!("Invalid value: {:?}", value)),
+    };
+
+    let timestamp = match timestamp {
+        Some(timestamp) => parse_timestamp(timestamp)?,
+        None => None,
+    };
+
+    Ok(GraphiteMetric {
+        dimension,
+        value,
+        timestamp,
+    })
+}
+
+/// Splits a path into the metric's name and any `;key=value` tags.
+fn parse_path(path: &str) -> Result<Dimension, String> {
+    let mut parts = path.split(';');
+    let name = parts.next().unwrap_or("");
+
+    if name.is_empty() {
+        return Err(format!("Missing metric name: {:?}", path))
+    }
+
+    let mut dimension = Dimension::with_name(name);
+    for tag in parts {
+        let mut pair = tag.splitn(2, '=');
+        match (pair.next(), pair.next()) {
+            (Some(key), Some(value)) if !key.is_empty() && !value.is_empty() => {
+                dimension = dimension.tagged(key, value)
+            },
+            _ => return Err(format!("Invalid tag: {:?}", tag)),
+        }
+    }
+
+    Ok(dimension)
+}
+
+/// Carbon accepts fractional timestamps (which it truncates) and `-1` for
+/// "now".
+fn parse_timestamp(timestamp: &str) -> Result<Option<i64>, String> {
+    let t = f64::from_str(timestamp).map_err(|_| format!("Invalid timestamp: {:?}", timestamp))?;
+
+    if t == -1.0 {
+        Ok(None)
+    } else if t.is_finite() && t >= 0.0 {
+        Ok(Some(t as i64))
+    } else {
+        Err(format!("Invalid timestamp: {:?}", timestamp))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_line() {
+        assert_eq!(parse_line("servers.web1.load 0.42 1500000000"), Ok(GraphiteMetric {
+            dimension: Dimension::with_name("servers.web1.load"),
+            value: 0.42,
+            timestamp: Some(1500000000),
+        }));
+    }
+
+    #[test]
+    fn it_parses_line_without_timestamp() {
+        let expected = GraphiteMetric {
+            dimension: Dimension::with_name("load"),
+            value: 1.0,
+            timestamp: None,
+        };
+
+        assert_eq!(parse_line("load 1"), Ok(expected));
+        assert_eq!(parse_line("load 1 -1").unwrap().timestamp, None);
+        assert_eq!(parse_line("load 1 1500000000.9").unwrap().timestamp, Some(1500000000));
+    }
+
+    #[test]
+    fn it_parses_tags() {
+        let metric = parse_line("disk.used;host=web.1;mount=/ 42 1500000000").unwrap();
+        assert_eq!(metric.dimension, Dimension::with_name("disk.used").tagged("host", "web.1").tagged("mount", "/"));
+    }
+
+    #[test]
+    fn it_rejects_invalid_lines() {
+        assert!(parse_line("load").is_err());
+        assert!(parse_line("load one 1500000000").is_err());
+        assert!(parse_line("load nan 1500000000").is_err());
+        assert!(parse_line("load 1 yesterday").is_err());
+        assert!(parse_line("load;host 1 1500000000").is_err());
+        assert!(parse_line("load 1 1500000000 extra").is_err());
+    }
+
+    #[test]
+    fn it_parses_every_valid_line_of_a_message() {
+        let parsed = parse_metrics("foo 1 1500000000\nbar oops 1500000000\n\nbaz 3 1500000000\n");
+
+        assert_eq!(parsed.metrics.len(), 2);
+        assert_eq!(parsed.errors.len(), 1);
+        assert_eq!(parsed.errors[0].offset, 17);
+    }
+
+    #[test]
+    fn it_converts_to_standard_metrics() {
+        let metric = parse_line("load 2 1500000000").unwrap();
+        assert_eq!(metric.to_standard_metric(), Metric::TimestampedSample(Dimension::with_name("load"), 2.0, 1500000000));
+
+        let metric = parse_line("load 2").unwrap();
+        assert_eq!(metric.to_standard_metric(), Metric::Sample(Dimension::with_name("load"), 2.0));
+    }
+}
@@ -0,0 +1,399 @@
+//! Parses the [InfluxDB line protocol][] spoken by Telegraf and other
+//! Influx agents:
+//!
+//! ```text
+//! cpu,host=web1,region=us-east usage_user=12.5,usage_system=3i 1500000000000000000
+//! ```
+//!
+//! Every numeric (or boolean) field becomes a sample named
+//! `measurement.field`, tagged with the point's tag set. A field called
+//! `value` is named after just the measurement. String fields can't be
+//! aggregated so they're skipped.
+//!
+//! [InfluxDB line protocol]: https://docs.influxdata.com/influxdb/v1.8/write_protocols/line_protocol_reference/
+
+use std::str::FromStr;
+
+use super::ParseError;
+use super::super::metrics::{Dimension, Metric, Tags};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldValue {
+    Float(f64),
+    Integer(i64),
+    UInteger(u64),
+    Boolean(bool),
+    String(String),
+}
+
+impl FieldValue {
+    /// Value to record for the field; `None` for strings.
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            FieldValue::Float(value)    => Some(value),
+            FieldValue::Integer(value)  => Some(value as f64),
+            FieldValue::UInteger(value) => Some(value as f64),
+            FieldValue::Boolean(value)  => Some(if value { 1.0 } else { 0.0 }),
+            FieldValue::String(_)       => None,
+        }
+    }
+}
+
+/// Unit of the timestamps in a write, from the `precision` parameter of the
+/// `/write` endpoint. Nanoseconds unless otherwise specified.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Precision {
+    #[default]
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+    Minutes,
+    Hours,
+}
+
+impl Precision {
+    /// Converts a timestamp in this precision to a Unix timestamp in
+    /// seconds, or `None` if it's too large to be one.
+    pub fn to_seconds(self, timestamp: i64) -> Option<i64> {
+        match self {
+            Precision::Nanoseconds  => Some(timestamp / 1_000_000_000),
+            Precision::Microseconds => Some(timestamp / 1_000_000),
+            Precision::Milliseconds => Some(timestamp / 1_000),
+            Precision::Seconds      => Some(timestamp),
+            Precision::Minutes      => timestamp.checked_mul(60),
+            Precision::Hours        => timestamp.checked_mul(3600),
+        }
+    }
+}
+
+impl FromStr for Precision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Precision, String> {
+        match s {
+            "n" | "ns" => Ok(Precision::Nanoseconds),
+            "u" | "us" => Ok(Precision::Microseconds),
+            "ms"       => Ok(Precision::Milliseconds),
+            "s"        => Ok(Precision::Seconds),
+            "m"        => Ok(Precision::Minutes),
+            "h"        => Ok(Precision::Hours),
+            _          => Err(format!("Invalid precision: {:?}", s)),
+        }
+    }
+}
+
+/// Point parsed from a line of line protocol.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InfluxPoint {
+    pub measurement: String,
+    pub tags: Tags,
+    pub fields: Vec<(String, FieldValue)>,
+    /// Timestamp in whatever precision the client wrote it in.
+    pub timestamp: Option<i64>,
+}
+
+impl InfluxPoint {
+    /// Converts every numeric field into a sample, timestamped if the point
+    /// has a timestamp (that's in range; `parse_points` rejects lines whose
+    /// timestamps aren't).
+    pub fn to_standard_metrics(&self, precision: Precision) -> Vec<Metric> {
+        let timestamp = self.timestamp.and_then(|t| precision.to_seconds(t));
+
+        self.fields.iter().filter_map(|(key, value)| {
+            let value = value.as_f64()?;
+
+            let name = if key == "value" {
+                self.measurement.clone()
+            } else {
+                format!("{}.{}", self.measurement, key)
+            };
+            let mut dimension = Dimension::with_name(name);
+            dimension.tags = self.tags.clone();
+
+            Some(match timestamp {
+                Some(timestamp) => Metric::TimestampedSample(dimension, value, timestamp),
+                None            => Metric::Sample(dimension, value),
+            })
+        }).collect()
+    }
+}
+
+/// Points parsed from a message along with errors for any lines that
+/// couldn't be parsed.
+#[derive(Debug, PartialEq)]
+pub struct ParsedPoints {
+    pub points: Vec<InfluxPoint>,
+    pub errors: Vec<ParseError>,
+}
+
+/// Parses a newline-separated message of points with timestamps in the given
+/// precision. Blank lines and comments (lines starting with `#`) are skipped.
+pub fn parse_points(message: &str, precision: Precision) -> ParsedPoints {
+    let mut parsed = ParsedPoints {
+        points: vec![],
+        errors: vec![],
+    };
+
+    let mut offset = 0;
+    for line in message.split('\n') {
+        let line_offset = offset;
+        offset += line.len() + 1;
+
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') { continue }
+
+        let result = parse_line(trimmed).and_then(|point| {
+            match point.timestamp {
+                Some(timestamp) if precision.to_seconds(timestamp).is_none() => {
+                    Err(format!("Timestamp out of range: {}", timestamp))
+                },
+                _ => Ok(point),
+            }
+        });
+
+        match result {
+            Ok(point) => parsed.points.push(point),
+            Err(description) => {
+                parsed.errors.push(ParseError {
+                    line: line.to_owned(),
+                    offset: line_offset,
+                    description,
+                })
+            },
+        }
+    }
+
+    parsed
+}
+
+/// Parses a single `measurement[,tags] fields [timestamp]` line.
+pub fn parse_line(line: &str) -> Result<InfluxPoint, String> {
+    let sections = split_unescaped(line, ' ');
+    let (series, fields, timestamp) = match sections.len() {
+        2 => (sections[0], sections[1], None),
+        3 => (sections[0], sections[1], Some(sections[2])),
+        _ => return Err("Expected `measurement[,tags] fields [timestamp]`".to_owned()),
+    };
+
+    let mut series = split_unescaped(series, ',').into_iter();
+    let measurement = unescape(series.next().unwrap_or(""));
+    if measurement.is_empty() {
+        return Err("Missing measurement".to_owned())
+    }
+
+    let mut tags = Tags::new();
+    for tag in series {
+        let (key, value) = split_pair(tag)?;
+        if value.is_empty() {
+            return Err(format!("Missing value for tag {:?}", key))
+        }
+        tags.insert(key, unescape(value));
+    }
+
+    let mut parsed_fields = vec![];
+    for field in split_unescaped(fields, ',') {
+        let (key, value) = split_pair(field)?;
+        parsed_fields.push((key, parse_field_value(value)?));
+    }
+
+    let timestamp = match timestamp {
+        Some(timestamp) => match i64::from_str(timestamp) {
+            Ok(timestamp) => Some(timestamp),
+            Err(_) => return Err(format!("Invalid timestamp: {:?}", timestamp)),
+        },
+        None => None,
+    };
+
+    Ok(InfluxPoint {
+        measurement,
+        tags,
+        fields: parsed_fields,
+        timestamp,
+    })
+}
+
+fn parse_field_value(value: &str) -> Result<FieldValue, String> {
+    let invalid = || format!("Invalid field value: {:?}", value);
+
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        return Ok(FieldValue::String(unescape(&value[1..value.len() - 1])))
+    }
+
+    match value {
+        "t" | "T" | "true" | "True" | "TRUE"    => return Ok(FieldValue::Boolean(true)),
+        "f" | "F" | "false" | "False" | "FALSE" => return Ok(FieldValue::Boolean(false)),
+        _ => {},
+    }
+
+    if let Some(integer) = value.strip_suffix('i') {
+        return i64::from_str(integer).map(FieldValue::Integer).map_err(|_| invalid())
+    }
+    if let Some(integer) = value.strip_suffix('u') {
+        return u64::from_str(integer).map(FieldValue::UInteger).map_err(|_| invalid())
+    }
+
+    match f64::from_str(value) {
+        Ok(float) if float.is_finite() => Ok(FieldValue::Float(float)),
+        _ => Err(invalid()),
+    }
+}
+
+/// Splits `key=value` on the first unescaped `=`, unescaping the key.
+fn split_pair(pair: &str) -> Result<(String, &str), String> {
+    let mut escaped = false;
+    for (index, c) in pair.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '=' if index > 0 => return Ok((unescape(&pair[..index]), &pair[index + 1..])),
+            _ => {},
+        }
+    }
+    Err(format!("Expected `key=value`, got {:?}", pair))
+}
+
+/// Splits on `separator` where it isn't escaped with a backslash or inside
+/// a double-quoted string field. Runs of spaces count as one, but empty
+/// comma-separated parts are kept so that they can be rejected.
+fn split_unescaped(s: &str, separator: char) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut start = 0;
+    let mut escaped = false;
+    let mut quoted = false;
+
+    for (index, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+            continue
+        }
+        match c {
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                if index > start || separator != ' ' { parts.push(&s[start..index]) }
+                start = index + 1;
+            },
+            _ => {},
+        }
+    }
+    if start < s.len() || separator != ' ' { parts.push(&s[start..]) }
+
+    parts
+}
+
+/// Removes the backslashes from escaped commas, spaces, equals signs,
+/// quotes, and backslashes. Other backslashes are kept.
+fn unescape(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(&next) = chars.peek() {
+                if [',', ' ', '=', '"', '\\'].contains(&next) {
+                    unescaped.push(next);
+                    chars.next();
+                    continue
+                }
+            }
+        }
+        unescaped.push(c);
+    }
+
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_line() {
+        let point = parse_line("cpu,host=web1,region=us-east usage_user=12.5,usage_system=3i,up=t 1500000000000000000").unwrap();
+
+        let mut tags = Tags::new();
+        tags.insert("host".to_owned(), "web1".to_owned());
+        tags.insert("region".to_owned(), "us-east".to_owned());
+
+        assert_eq!(point, InfluxPoint {
+            measurement: "cpu".to_owned(),
+            tags,
+            fields: vec![
+                ("usage_user".to_owned(), FieldValue::Float(12.5)),
+                ("usage_system".to_owned(), FieldValue::Integer(3)),
+                ("up".to_owned(), FieldValue::Boolean(true)),
+            ],
+            timestamp: Some(1500000000000000000),
+        });
+    }
+
+    #[test]
+    fn it_parses_escapes_and_strings() {
+        let point = parse_line(r#"disk\ io,path=C:\\data,name=a\,b\=c msg="hello, \"world\"",bytes=10u"#).unwrap();
+
+        assert_eq!(point.measurement, "disk io");
+        assert_eq!(point.tags.get("path").unwrap(), r"C:\data");
+        assert_eq!(point.tags.get("name").unwrap(), "a,b=c");
+        assert_eq!(point.fields, vec![
+            ("msg".to_owned(), FieldValue::String(r#"hello, "world""#.to_owned())),
+            ("bytes".to_owned(), FieldValue::UInteger(10)),
+        ]);
+        assert_eq!(point.timestamp, None);
+    }
+
+    #[test]
+    fn it_rejects_invalid_lines() {
+        assert!(parse_line("cpu").is_err());
+        assert!(parse_line("cpu usage").is_err());
+        assert!(parse_line("cpu usage=oops").is_err());
+        assert!(parse_line("cpu usage=1.5i").is_err());
+        assert!(parse_line("cpu,host usage=1").is_err());
+        assert!(parse_line("cpu usage=1 yesterday").is_err());
+        assert!(parse_line(",host=a usage=1").is_err());
+    }
+
+    #[test]
+    fn it_parses_every_valid_line_of_a_message() {
+        let parsed = parse_points("# comment\ncpu value=1\nmem oops\n\nmem used=2i\n", Precision::default());
+
+        assert_eq!(parsed.points.len(), 2);
+        assert_eq!(parsed.errors.len(), 1);
+        assert_eq!(parsed.errors[0].offset, 22);
+    }
+
+    #[test]
+    fn it_converts_to_standard_metrics() {
+        let point = parse_line("cpu,host=web1 value=1,idle=t,msg=\"hi\" 1500000000000").unwrap();
+        let dimension = Dimension::with_name("cpu").tagged("host", "web1");
+
+        assert_eq!(point.to_standard_metrics(Precision::Milliseconds), vec![
+            Metric::TimestampedSample(dimension.clone(), 1.0, 1500000000),
+            Metric::TimestampedSample(Dimension::with_name("cpu.idle").tagged("host", "web1"), 1.0, 1500000000),
+        ]);
+
+        let point = parse_line("cpu,host=web1 value=2").unwrap();
+        assert_eq!(point.to_standard_metrics(Precision::default()), vec![
+            Metric::Sample(dimension, 2.0),
+        ]);
+    }
+
+    #[test]
+    fn it_parses_precisions() {
+        assert_eq!("ms".parse(), Ok(Precision::Milliseconds));
+        assert_eq!("u".parse(), Ok(Precision::Microseconds));
+        assert!("weeks".parse::<Precision>().is_err());
+        assert_eq!(Precision::Hours.to_seconds(2), Some(7200));
+        assert_eq!(Precision::Hours.to_seconds(i64::MAX), None);
+    }
+
+    #[test]
+    fn it_rejects_out_of_range_timestamps() {
+        let parsed = parse_points("cpu value=1 9223372036854775807\ncpu value=2 2\n", Precision::Hours);
+
+        assert_eq!(parsed.points.len(), 1);
+        assert_eq!(parsed.errors.len(), 1);
+        assert_eq!(parsed.errors[0].offset, 0);
+        assert_eq!(parsed.errors[0].to_string(), "Timestamp out of range: 9223372036854775807 at byte 0: \"cpu value=1 9223372036854775807\"");
+    }
+}
@@ -1,17 +1,28 @@
 use regex::{Regex};
 use std::str::{FromStr};
 
+use super::logplex::LogplexMessage;
 use super::super::metrics::*;
 
 /// Reader that takes a log line string and returns any metrics found in it.
 pub trait LogLineReader: Send + Sync {
     fn read(&self, line: &str) -> Vec<Metric>;
+
+    /// Reads a message delivered by a Heroku log drain. By default just the
+    /// message body is passed to `read`.
+    fn read_logplex_message(&self, message: &LogplexMessage) -> Vec<Metric> {
+        self.read(message.message)
+    }
 }
 
 /// Reads metrics from log lines in the standard formats:
 ///
 /// - Measures: `measure#metric=1.2`
 /// - Counts: `count#metric=3`
+/// - Samples: `sample#metric=4.5`
+///
+/// Any `tag#key=value` pairs in the line are added as tags to all of the
+/// line's metrics.
 pub struct StandardLogLineReader;
 
 lazy_static! {
@@ -26,6 +37,9 @@ lazy_static! {
 
     static ref SOURCE_REGEX: Regex =
         Regex::new(r"source=([[:alnum:]._]+)").unwrap();
+
+    static ref LOG_TAG_REGEX: Regex =
+        Regex::new(r"tag#([[:alnum:]._]+)=([^[:space:]]+)").unwrap();
 }
 
 impl StandardLogLineReader {
@@ -34,13 +48,20 @@ impl StandardLogLineReader {
                     .and_then(|c| c.get(1))
                     .map(|m| m.as_str())
     }
+
+    fn parse_tags(line: &str) -> Tags {
+        LOG_TAG_REGEX.captures_iter(line)
+                     .map(|c| (c.get(1).unwrap().as_str().to_owned(), c.get(2).unwrap().as_str().to_owned()))
+                     .collect()
+    }
 }
 
 impl LogLineReader for StandardLogLineReader {
     fn read(&self, line: &str) -> Vec<Metric> {
         let source = StandardLogLineReader::parse_source(line).map(|s| s.to_owned());
+        let tags = StandardLogLineReader::parse_tags(line);
         let dimension = |name: &str| {
-            Dimension { name: name.to_owned(), source: source.clone() }
+            Dimension { name: name.to_owned(), source: source.clone(), tags: tags.clone() }
         };
 
         let mut metrics = vec![];
@@ -49,7 +70,7 @@ impl LogLineReader for StandardLogLineReader {
         for cap in LOG_COUNT_REGEX.captures_iter(line) {
             let name = cap.get(1).unwrap().as_str();
 
-            if let Ok(value) = u64::from_str(cap.get(2).unwrap().as_str()) {
+            if let Ok(value) = i64::from_str(cap.get(2).unwrap().as_str()) {
                 metrics.push(Count(dimension(name), value))
             }
         }
@@ -139,24 +160,24 @@ impl HerokuLogLineReader {
                 None => return None,
             };
 
-        let base = format!("dyno.{}", dyno_type);
+        let dimension = |name: &str| {
+            Dimension::with_name_and_source(name, dyno_type).tagged("dyno_type", dyno_type)
+        };
 
         // Counting a 499 as a 500
         let is_500 = status >= 499 && status < 600;
 
         // Don't record timing for 499 and 5xx errors
         if !is_500 {
-            let service_time_name = format!("{}.service_time", base);
-            metrics.push(Measure(Dimension::with_name_and_source(service_time_name, dyno_type), service as f64));
+            metrics.push(Measure(dimension("dyno.service_time"), service as f64));
         }
 
         // Track the connect time (how long it took to pick up the request)
-        let connect_time_name = format!("{}.connect_time", base);
-        metrics.push(Measure(Dimension::with_name_and_source(connect_time_name, dyno_type), connect as f64));
+        metrics.push(Measure(dimension("dyno.connect_time"), connect as f64));
 
         // Count the status
-        let status_name = format!("{}.status.{}", base, status);
-        metrics.push(Count(Dimension::with_name_and_source(status_name, dyno_type), 1));
+        let status_dimension = dimension("dyno.status").tagged("status", status.to_string());
+        metrics.push(Count(status_dimension, 1));
 
         Some(metrics)
     }
@@ -178,8 +199,7 @@ impl HerokuLogLineReader {
             return None
         }
 
-        let name = format!("heroku.error.{}", code);
-        Some(Count(Dimension::with_name(name), 1))
+        Some(Count(Dimension::with_name("heroku.error").tagged("code", code), 1))
     }
 
     /// Parses the `sample#load_avg_1m=` metrics from Heroku logs.
@@ -202,15 +222,11 @@ impl HerokuLogLineReader {
             None => return None,
         };
 
-        let dim = Dimension::with_name_and_source(format!("dyno.{}.load_avg_1m", dyno_type), source);
+        let dim = Dimension::with_name_and_source("dyno.load_avg_1m", source).tagged("dyno_type", dyno_type);
         Some(Measure(dim, load_avg_1m))
     }
-}
-
-impl LogLineReader for HerokuLogLineReader {
-    fn read(&self, line: &str) -> Vec<Metric> {
-        if !line.contains("heroku") { return vec![] }
 
+    fn read_metrics(line: &str) -> Vec<Metric> {
         let mut metrics: Vec<Metric> = vec![];
 
         if let Some(statuses) = HerokuLogLineReader::parse_status(line)      { metrics.extend(statuses) }
@@ -221,6 +237,22 @@ impl LogLineReader for HerokuLogLineReader {
     }
 }
 
+impl LogLineReader for HerokuLogLineReader {
+    fn read(&self, line: &str) -> Vec<Metric> {
+        if !line.contains("heroku") { return vec![] }
+
+        HerokuLogLineReader::read_metrics(line)
+    }
+
+    /// Heroku's own messages are the ones logged by the `heroku` app name
+    /// (their bodies needn't mention Heroku at all).
+    fn read_logplex_message(&self, message: &LogplexMessage) -> Vec<Metric> {
+        if message.app_name != "heroku" { return vec![] }
+
+        HerokuLogLineReader::read_metrics(message.message)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::{
@@ -228,6 +260,7 @@ mod tests {
         StandardLogLineReader,
         HerokuLogLineReader
     };
+    use super::super::logplex::parse_message;
     use super::super::super::metrics::*;
 
     #[test]
@@ -252,6 +285,17 @@ mod tests {
         )
     }
 
+    #[test]
+    fn standard_reader_reads_measure_with_tags() {
+        let reader = StandardLogLineReader;
+        let line = "tag#route=/users tag#region=us-east measure#foo=1.2\n";
+
+        assert_eq!(
+            reader.read(line),
+            vec![ Measure(Dimension::with_name("foo").tagged("route", "/users").tagged("region", "us-east"), 1.2) ]
+        )
+    }
+
     #[test]
     fn standard_reader_reads_count() {
         let reader = StandardLogLineReader;
@@ -302,7 +346,7 @@ mod tests {
 
         assert_eq!(
             reader.read(line),
-            vec![ Measure(Dimension::with_name_and_source("dyno.web.load_avg_1m", "web.2"), 0.56) ]
+            vec![ Measure(Dimension::with_name_and_source("dyno.load_avg_1m", "web.2").tagged("dyno_type", "web"), 0.56) ]
         )
     }
 
@@ -314,9 +358,9 @@ mod tests {
         assert_eq!(
             reader.read(line),
             vec![
-                Measure(Dimension::with_name_and_source("dyno.web.connect_time", "web"), 0.0),
-                Count(Dimension::with_name_and_source("dyno.web.status.503", "web"), 1),
-                Count(Dimension::with_name("heroku.error.H18"), 1),
+                Measure(Dimension::with_name_and_source("dyno.connect_time", "web").tagged("dyno_type", "web"), 0.0),
+                Count(Dimension::with_name_and_source("dyno.status", "web").tagged("dyno_type", "web").tagged("status", "503"), 1),
+                Count(Dimension::with_name("heroku.error").tagged("code", "H18"), 1),
             ]
         )
     }
@@ -329,7 +373,7 @@ mod tests {
         assert_eq!(
             reader.read(line),
             vec![
-                Count(Dimension::with_name("heroku.error.R14"), 1),
+                Count(Dimension::with_name("heroku.error").tagged("code", "R14"), 1),
             ]
         )
     }
@@ -342,10 +386,20 @@ mod tests {
         assert_eq!(
             reader.read(line),
             vec![
-                Measure(Dimension::with_name_and_source("dyno.web.service_time", "web"), 39.0),
-                Measure(Dimension::with_name_and_source("dyno.web.connect_time", "web"), 1.0),
-                Count(Dimension::with_name_and_source("dyno.web.status.200", "web"), 1),
+                Measure(Dimension::with_name_and_source("dyno.service_time", "web").tagged("dyno_type", "web"), 39.0),
+                Measure(Dimension::with_name_and_source("dyno.connect_time", "web").tagged("dyno_type", "web"), 1.0),
+                Count(Dimension::with_name_and_source("dyno.status", "web").tagged("dyno_type", "web").tagged("status", "200"), 1),
             ]
         )
     }
+
+    #[test]
+    fn heroku_reader_reads_logplex_messages() {
+        let reader = HerokuLogLineReader;
+        let router = parse_message("<158>1 2016-02-26T21:34:59.370813+00:00 host heroku router - at=info method=PUT path=\"/\" host=www.example.com dyno=web.1 connect=1ms service=39ms status=200 bytes=1627").unwrap();
+        let app = parse_message("<190>1 2016-02-26T21:34:59.370813+00:00 host app web.1 - Error R14 is just a string here").unwrap();
+
+        assert_eq!(reader.read_logplex_message(&router).len(), 3);
+        assert_eq!(reader.read_logplex_message(&app), vec![]);
+    }
 }
@@ -0,0 +1,162 @@
+//! Parses the bodies that Heroku's [HTTPS log drains][] POST: [RFC 5424][]
+//! syslog messages framed with [RFC 6587][] octet counting, where each
+//! message is preceded by its length in bytes:
+//!
+//! ```text
+//! 83 <40>1 2012-11-30T06:45:29+00:00 host app web.3 - State changed from starting to up
+//! ```
+//!
+//! [HTTPS log drains]: https://devcenter.heroku.com/articles/log-drains#https-drains
+//! [RFC 5424]: https://tools.ietf.org/html/rfc5424
+//! [RFC 6587]: https://tools.ietf.org/html/rfc6587#section-3.4.1
+
+use super::ParseError;
+
+/// Syslog message delivered by a Logplex drain.
+#[derive(Debug, PartialEq)]
+pub struct LogplexMessage<'a> {
+    /// Syslog priority: `facility * 8 + severity`.
+    pub priority: u8,
+    pub version: u8,
+    /// RFC 3339 timestamp, as sent.
+    pub timestamp: &'a str,
+    pub hostname: &'a str,
+    /// `heroku` for messages from the platform (router, dyno metrics and
+    /// errors), otherwise `app`.
+    pub app_name: &'a str,
+    /// Dyno (eg. `web.1`) or component (eg. `router`) that logged it.
+    pub proc_id: &'a str,
+    pub msg_id: &'a str,
+    /// The log line itself without its framing or trailing newline. Unlike
+    /// a line of a raw log drain this may span several lines.
+    pub message: &'a str,
+    /// `Logplex-Drain-Token` the message was delivered with, which
+    /// identifies the drain (and so the app) it came from.
+    pub drain_token: Option<&'a str>,
+}
+
+/// Splits a drain body into its frames and parses each of them. Framing
+/// errors can't be recovered from since the following frames' boundaries
+/// aren't known, so the first error fails the whole body.
+pub fn parse_frames(body: &str) -> Result<Vec<LogplexMessage<'_>>, ParseError> {
+    let mut messages = vec![];
+    let mut offset = 0;
+
+    loop {
+        // Frames may be separated by newlines.
+        let rest = &body[offset..];
+        let frame = rest.trim_start();
+        offset += rest.len() - frame.len();
+        if frame.is_empty() { break }
+
+        let frame_offset = offset;
+        let error = |description: String| ParseError {
+            line: frame.lines().next().unwrap_or("").to_owned(),
+            offset: frame_offset,
+            description,
+        };
+
+        let space = frame.find(' ').ok_or_else(|| error("Expected an octet count".to_owned()))?;
+        let length: usize = frame[..space].parse()
+            .map_err(|_| error(format!("Invalid octet count {:?}", &frame[..space])))?;
+
+        let start = space + 1;
+        if length > frame.len() - start {
+            return Err(error(format!("Frame is {} bytes but only {} are left", length, frame.len() - start)))
+        }
+        let message = frame.get(start..start + length)
+            .ok_or_else(|| error("Frame doesn't end on a character boundary".to_owned()))?;
+
+        messages.push(parse_message(message).map_err(error)?);
+        offset += start + length;
+    }
+
+    Ok(messages)
+}
+
+/// Parses a single `<PRI>VERSION TIMESTAMP HOSTNAME APP-NAME PROCID MSGID MSG`
+/// syslog message. Heroku doesn't send structured data, so everything after
+/// the `MSGID` is the message.
+pub fn parse_message(message: &str) -> Result<LogplexMessage<'_>, String> {
+    let message = message.trim_end_matches(['\r', '\n']);
+    let mut fields = message.splitn(7, ' ');
+
+    let header = fields.next().unwrap_or("");
+    let close = match (header.starts_with('<'), header.find('>')) {
+        (true, Some(close)) => close,
+        _ => return Err(format!("Expected `<priority>version`, got {:?}", header)),
+    };
+    let priority = match header[1..close].parse() {
+        Ok(priority) if priority <= 191 => priority,
+        _ => return Err(format!("Invalid priority {:?}", &header[1..close])),
+    };
+    let version = header[close + 1..].parse()
+        .map_err(|_| format!("Invalid version {:?}", &header[close + 1..]))?;
+
+    let mut next = |name: &str| fields.next().ok_or_else(|| format!("Missing {}", name));
+    let timestamp = next("timestamp")?;
+    let hostname = next("hostname")?;
+    let app_name = next("app name")?;
+    let proc_id = next("proc ID")?;
+    let msg_id = next("message ID")?;
+
+    Ok(LogplexMessage {
+        priority,
+        version,
+        timestamp,
+        hostname,
+        app_name,
+        proc_id,
+        msg_id,
+        message: fields.next().unwrap_or(""),
+        drain_token: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_frames, parse_message, LogplexMessage};
+
+    #[test]
+    fn it_parses_messages() {
+        assert_eq!(parse_message("<40>1 2012-11-30T06:45:29+00:00 host app web.3 - State changed\n"), Ok(LogplexMessage {
+            priority: 40,
+            version: 1,
+            timestamp: "2012-11-30T06:45:29+00:00",
+            hostname: "host",
+            app_name: "app",
+            proc_id: "web.3",
+            msg_id: "-",
+            message: "State changed",
+            drain_token: None,
+        }));
+
+        assert!(parse_message("40 2012-11-30T06:45:29+00:00 host app web.3 - Up").is_err());
+        assert!(parse_message("<40>1 2012-11-30T06:45:29+00:00 host app").is_err());
+    }
+
+    #[test]
+    fn it_parses_frames() {
+        let body = "83 <40>1 2012-11-30T06:45:29+00:00 host app web.3 - State changed from starting to up\n\
+                    65 <40>1 2012-11-30T06:45:30+00:00 host app web.3 - Error:\n  at foo\n\n";
+
+        let messages = parse_frames(body).unwrap();
+
+        let bodies: Vec<&str> = messages.iter().map(|message| message.message).collect();
+        assert_eq!(bodies, vec!["State changed from starting to up", "Error:\n  at foo"]);
+    }
+
+    #[test]
+    fn it_rejects_bad_framing() {
+        let error = parse_frames("83 <40>1 2012-11-30T06:45:29+00:00 host app web.3 - Up\n").unwrap_err();
+        assert_eq!(error.offset, 0);
+        assert_eq!(error.to_string(), "Frame is 83 bytes but only 52 are left at byte 0: \"83 <40>1 2012-11-30T06:45:29+00:00 host app web.3 - Up\"");
+
+        let error = parse_frames("\nx <40>1").unwrap_err();
+        assert_eq!(error.offset, 1);
+        assert_eq!(error.to_string(), "Invalid octet count \"x\" at byte 1: \"x <40>1\"");
+
+        let error = parse_frames("18446744073709551615 <40>1 2012-11-30T06:45:29+00:00 host app web.3 - Up").unwrap_err();
+        assert_eq!(error.to_string(), "Frame is 18446744073709551615 bytes but only 51 are left at byte 0: \"18446744073709551615 <40>1 2012-11-30T06:45:29+00:00 host app web.3 - Up\"");
+    }
+}
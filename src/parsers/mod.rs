@@ -2,5 +2,34 @@
 //!
 //! This is used mainly by collectors.
 
+use std::error;
+use std::fmt;
+
 pub mod statsd;
+pub mod dogstatsd;
+pub mod graphite;
+pub mod influx;
 pub mod log_line;
+pub mod logplex;
+
+/// Error from parsing a single line of a message.
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    /// The line that failed to parse.
+    pub line: String,
+    /// Byte offset of the start of the line in the message.
+    pub offset: usize,
+    description: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at byte {}: {:?}", self.description, self.offset, self.line)
+    }
+}
+
+impl error::Error for ParseError {
+    fn description(&self) -> &str {
+        &self.description
+    }
+}
@@ -1,123 +1,302 @@
 use std::char;
-use std::error;
-use std::fmt;
-use std::num::ParseIntError;
 use std::str::{self, FromStr};
 
 use nom::{
     digit,
     is_alphanumeric,
+    is_digit,
     IResult
 };
 
-use super::super::metrics::{Dimension, Metric};
+use super::dogstatsd::{parse_event, parse_service_check};
+pub use super::ParseError;
+use super::super::metrics::{Dimension, Event, Metric, ServiceCheck, Tags};
 
-/// Parsed StatsD metric.
+/// Parsed StatsD metric. Counters and timers carry the sample rate the
+/// client sent them at (if any). The dimension holds the metric's name and
+/// any [DogStatsD][] tags (`|#env:prod,route:/users`) it was sent with.
 ///
 /// See here for more details: https://github.com/b/statsd_spec#metric-types--formats
+///
+/// [DogStatsD]: https://docs.datadoghq.com/developers/dogstatsd/datagram_shell/
 #[derive(Debug, PartialEq)]
 pub enum ParsedMetric {
-    Counter(String, u64),
-    Gauge(String, u64),
-    Timer(String, u64),
+    Counter(Dimension, i64, Option<f64>),
+    Gauge(Dimension, f64),
+    /// Gauge sent with a leading `+` or `-`: it adjusts the gauge's current
+    /// value rather than replacing it.
+    GaugeDelta(Dimension, f64),
+    Timer(Dimension, f64, Option<f64>),
+    /// Histograms are aggregated exactly like timers.
+    Histogram(Dimension, f64, Option<f64>),
+    /// Distribution values are forwarded as-is rather than being aggregated
+    /// into percentiles by the store.
+    Distribution(Dimension, f64, Option<f64>),
+    Set(Dimension, String),
 }
 
 impl ParsedMetric {
     pub fn to_standard_metric(&self) -> Metric {
         use self::ParsedMetric::*;
 
-        match self {
-            &Counter(ref name, value) => Metric::Count(Dimension::with_name(name), value),
-            &Gauge(ref name, value)   => Metric::Sample(Dimension::with_name(name), value as f64),
-            &Timer(ref name, value)   => Metric::Measure(Dimension::with_name(name), value as f64),
+        match *self {
+            Counter(ref dim, value, None)         => Metric::Count(dim.clone(), value),
+            Counter(ref dim, value, Some(rate))   => Metric::SampledCount(dim.clone(), value, rate),
+            Gauge(ref dim, value)                 => Metric::Sample(dim.clone(), value),
+            GaugeDelta(ref dim, value)            => Metric::SampleDelta(dim.clone(), value),
+            Timer(ref dim, value, None)           => Metric::Measure(dim.clone(), value),
+            Timer(ref dim, value, Some(rate))     => Metric::SampledMeasure(dim.clone(), value, rate),
+            Histogram(ref dim, value, None)       => Metric::Measure(dim.clone(), value),
+            Histogram(ref dim, value, Some(rate)) => Metric::SampledMeasure(dim.clone(), value, rate),
+            // Raw distribution points can't be weighted, so the sample rate
+            // is dropped.
+            Distribution(ref dim, value, _)       => Metric::Distribution(dim.clone(), value),
+            Set(ref dim, ref value)               => Metric::Set(dim.clone(), value.clone()),
         }
     }
 }
 
 pub type ParseResult<'a> = IResult<&'a [u8], ParsedMetric>;
 
-// Convert a byte array to a `u64` result.
-fn bytes_to_u64(i: &[u8]) -> Result<u64, ParseIntError> {
+// Convert a byte array to any type that can be parsed from a string (eg.
+// `u64`, `i64`, or `f64`).
+fn bytes_to<T: FromStr>(i: &[u8]) -> Result<T, T::Err> {
     let s = str::from_utf8(i).unwrap();
 
-    u64::from_str(s)
+    T::from_str(s)
 }
 
+/// Metrics parsed from a StatsD message along with errors for any lines
+/// that couldn't be parsed. DogStatsD events and service checks in the
+/// message are included too.
 #[derive(Debug, PartialEq)]
-pub struct ParseError {
-    description: String,
+pub struct ParsedMetrics {
+    pub metrics: Vec<ParsedMetric>,
+    pub events: Vec<Event>,
+    pub service_checks: Vec<ServiceCheck>,
+    pub errors: Vec<ParseError>,
 }
 
-impl fmt::Display for ParseError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.description)
+/// Parses a newline-separated StatsD message. Every line is parsed on its
+/// own so that a malformed line doesn't prevent the rest of the message's
+/// metrics from being parsed. Blank lines are skipped.
+pub fn parse_metrics(i: &[u8]) -> ParsedMetrics {
+    let mut parsed = ParsedMetrics {
+        metrics: vec![],
+        events: vec![],
+        service_checks: vec![],
+        errors: vec![],
+    };
+
+    let mut offset = 0;
+    for line in i.split(|&c| c == b'\n') {
+        let line_offset = offset;
+        offset += line.len() + 1;
+
+        let trimmed = trim_end(line);
+        if trimmed.is_empty() { continue }
+
+        let result = if trimmed.starts_with(b"_e{") {
+            complete!(trimmed, parse_event).map(Line::Event)
+        } else if trimmed.starts_with(b"_sc|") {
+            complete!(trimmed, parse_service_check).map(Line::ServiceCheck)
+        } else {
+            parse_metric(trimmed).map(Line::Metric)
+        };
+
+        let description = match result {
+            IResult::Done(remaining, line) => {
+                if remaining.is_empty() {
+                    match line {
+                        Line::Metric(metric)      => parsed.metrics.push(metric),
+                        Line::Event(event)        => parsed.events.push(event),
+                        Line::ServiceCheck(check) => parsed.service_checks.push(check),
+                    }
+                    continue
+                }
+                format!("Unexpected input after metric: {:?}", String::from_utf8_lossy(remaining))
+            },
+            IResult::Error(err) => format!("{:?}", err),
+            IResult::Incomplete(_) => "Incomplete metric".to_owned(),
+        };
+
+        parsed.errors.push(ParseError {
+            line: String::from_utf8_lossy(line).into_owned(),
+            offset: line_offset,
+            description,
+        });
     }
+
+    parsed
 }
 
-impl error::Error for ParseError {
-    fn description(&self) -> &str {
-        &self.description
-    }
+// What a single line of a message can hold.
+enum Line {
+    Metric(ParsedMetric),
+    Event(Event),
+    ServiceCheck(ServiceCheck),
 }
 
-pub fn parse_metrics(i: &[u8]) -> Result<Vec<ParsedMetric>, ParseError> {
-    let result = complete!(i,
-        separated_nonempty_list!(
-            tag!("\n"),
-            alt_complete!(
-                parse_counter |
-                parse_gauge |
-                parse_timer
-            )
+/// Parses a single metric of any type.
+pub fn parse_metric(i: &[u8]) -> ParseResult<'_> {
+    complete!(i,
+        alt_complete!(
+            parse_counter |
+            parse_gauge |
+            parse_timer |
+            parse_histogram |
+            parse_distribution |
+            parse_set
         )
-    );
+    )
+}
 
-    match result {
-        IResult::Done(_, metrics) => Ok(metrics),
-        IResult::Error(err) => Err(ParseError { description: format!("{:?}", err) }),
-        IResult::Incomplete(_) => unreachable!("Cannot be Incomplete"),
-    }
+fn trim_end(line: &[u8]) -> &[u8] {
+    let end = line.iter().rposition(|c| !(*c as char).is_whitespace()).map(|p| p + 1).unwrap_or(0);
+    &line[..end]
 }
 
-pub fn parse_counter(i: &[u8]) -> ParseResult {
+pub fn parse_counter(i: &[u8]) -> ParseResult<'_> {
     do_parse!(i,
         name: parse_metric_name >> tag!(":")  >>
-        value: parse_value      >> tag!("|c") >>
-        _sample_rate: opt!(complete!(parse_sample_rate)) >>
-        (ParsedMetric::Counter(name, value))
+        value: parse_integer    >> tag!("|c") >>
+        sample_rate: opt!(complete!(parse_sample_rate)) >>
+        tags: parse_tags >>
+        (ParsedMetric::Counter(dimension(name, tags), value, sample_rate))
     )
 }
 
-pub fn parse_gauge(i: &[u8]) -> ParseResult {
+pub fn parse_gauge(i: &[u8]) -> ParseResult<'_> {
     do_parse!(i,
         name: parse_metric_name >> tag!(":")  >>
+        sign: opt!(peek!(one_of!("+-"))) >>
         value: parse_value      >> tag!("|g") >>
-        (ParsedMetric::Gauge(name, value))
+        tags: parse_tags >>
+        (match sign {
+            Some(_) => ParsedMetric::GaugeDelta(dimension(name, tags), value),
+            None    => ParsedMetric::Gauge(dimension(name, tags), value),
+        })
     )
 }
 
-pub fn parse_timer(i: &[u8]) -> ParseResult {
+pub fn parse_timer(i: &[u8]) -> ParseResult<'_> {
     do_parse!(i,
         name: parse_metric_name >> tag!(":")  >>
         value: parse_value      >> tag!("|ms") >>
-        (ParsedMetric::Timer(name, value))
+        sample_rate: opt!(complete!(parse_sample_rate)) >>
+        tags: parse_tags >>
+        (ParsedMetric::Timer(dimension(name, tags), value, sample_rate))
+    )
+}
+
+pub fn parse_histogram(i: &[u8]) -> ParseResult<'_> {
+    do_parse!(i,
+        name: parse_metric_name >> tag!(":")  >>
+        value: parse_value      >> tag!("|h") >>
+        sample_rate: opt!(complete!(parse_sample_rate)) >>
+        tags: parse_tags >>
+        (ParsedMetric::Histogram(dimension(name, tags), value, sample_rate))
     )
 }
 
-pub fn parse_value(i: &[u8]) -> IResult<&[u8], u64> {
+pub fn parse_distribution(i: &[u8]) -> ParseResult<'_> {
+    do_parse!(i,
+        name: parse_metric_name >> tag!(":")  >>
+        value: parse_value      >> tag!("|d") >>
+        sample_rate: opt!(complete!(parse_sample_rate)) >>
+        tags: parse_tags >>
+        (ParsedMetric::Distribution(dimension(name, tags), value, sample_rate))
+    )
+}
+
+pub fn parse_set(i: &[u8]) -> ParseResult<'_> {
+    #[inline]
+    fn is_set_value_char(i: u8) -> bool {
+        i != b'|' && i != b'\n'
+    }
+
+    do_parse!(i,
+        name: parse_metric_name >> tag!(":")  >>
+        value: map!(take_while1!(is_set_value_char), |value| {
+            String::from_utf8_lossy(value).into_owned()
+        }) >>
+        tag!("|s") >>
+        tags: parse_tags >>
+        (ParsedMetric::Set(dimension(name, tags), value))
+    )
+}
+
+/// Parses a floating-point value. Decimals (`12.5`), exponents (`1e3`) and
+/// signs (`-3`) are all accepted.
+pub fn parse_value(i: &[u8]) -> IResult<&[u8], f64> {
+    #[inline]
+    fn is_value_char(i: u8) -> bool {
+        is_digit(i) || i == b'.' || i == b'-' || i == b'+' || i == b'e' || i == b'E'
+    }
+
     map_res!(i,
-        digit,
-        |value| { bytes_to_u64(value) }
+        take_while1!(is_value_char),
+        |value| { bytes_to::<f64>(value) }
     )
 }
 
-pub fn parse_sample_rate(i: &[u8]) -> IResult<&[u8], u64> {
+/// Parses a whole number value with an optional leading minus sign.
+pub fn parse_integer(i: &[u8]) -> IResult<&[u8], i64> {
+    map_res!(i,
+        recognize!(pair!(opt!(tag!("-")), digit)),
+        |value| { bytes_to::<i64>(value) }
+    )
+}
+
+/// Parses a `|@0.1` sample rate. The rate must be greater than zero and no
+/// more than one.
+pub fn parse_sample_rate(i: &[u8]) -> IResult<&[u8], f64> {
     preceded!(i,
         tag!("|@"),
-        map_res!(digit, |rate| { bytes_to_u64(rate) })
+        verify!(parse_value, |rate: f64| { rate > 0.0 && rate <= 1.0 })
     )
 }
 
+/// Parses the optional DogStatsD tags section (`|#env:prod,beta`) at the
+/// end of a metric. Tags without a value (`beta`) get an empty value.
+pub fn parse_tags(i: &[u8]) -> IResult<&[u8], Tags> {
+    #[inline]
+    fn is_tag_char(i: u8) -> bool {
+        i != b',' && i != b'|' && i != b'\n'
+    }
+
+    fn to_pair(tag: &[u8]) -> (String, String) {
+        let tag = String::from_utf8_lossy(tag);
+        let mut parts = tag.splitn(2, ':');
+        let key = parts.next().unwrap_or("").to_owned();
+        let value = parts.next().unwrap_or("").to_owned();
+        (key, value)
+    }
+
+    fn parse_tag(i: &[u8]) -> IResult<&[u8], (String, String)> {
+        map!(i, take_while1!(is_tag_char), to_pair)
+    }
+
+    fn parse_tag_list(i: &[u8]) -> IResult<&[u8], Vec<(String, String)>> {
+        preceded!(i, tag!("|#"), separated_nonempty_list!(tag!(","), parse_tag))
+    }
+
+    map!(i,
+        opt!(complete!(parse_tag_list)),
+        |tags: Option<Vec<(String, String)>>| {
+            tags.map(|tags| tags.into_iter().collect()).unwrap_or_default()
+        }
+    )
+}
+
+fn dimension(name: String, tags: Tags) -> Dimension {
+    Dimension {
+        name,
+        source: None,
+        tags,
+    }
+}
+
 fn parse_metric_name(i: &[u8]) -> IResult<&[u8], String> {
     #[inline]
     fn is_metric_name_char(i: u8) -> bool {
@@ -138,6 +317,7 @@ mod tests {
 
     use std::any::Any;
     use nom::IResult;
+    use super::super::super::metrics::ServiceCheckStatus;
 
     fn complete<'a, T>(value: T) -> IResult<&'a [u8], T>
         where T: Any {
@@ -148,7 +328,39 @@ mod tests {
     fn it_parses_gauge() {
         assert_eq!(
             parse_gauge(&b"foo.bar_baz:12|g"[..]),
-            complete(ParsedMetric::Gauge("foo.bar_baz".to_owned(), 12))
+            complete(ParsedMetric::Gauge(Dimension::with_name("foo.bar_baz"), 12.0))
+        )
+    }
+
+    #[test]
+    fn it_parses_gauge_with_decimal() {
+        assert_eq!(
+            parse_gauge(&b"foo:12.5|g"[..]),
+            complete(ParsedMetric::Gauge(Dimension::with_name("foo"), 12.5))
+        )
+    }
+
+    #[test]
+    fn it_parses_gauge_delta() {
+        assert_eq!(
+            parse_gauge(&b"temp:-3|g"[..]),
+            complete(ParsedMetric::GaugeDelta(Dimension::with_name("temp"), -3.0))
+        );
+        assert_eq!(
+            parse_gauge(&b"queue.depth:+4.5|g"[..]),
+            complete(ParsedMetric::GaugeDelta(Dimension::with_name("queue.depth"), 4.5))
+        )
+    }
+
+    #[test]
+    fn it_parses_gauge_with_exponent() {
+        assert_eq!(
+            parse_gauge(&b"foo:1.5e3|g"[..]),
+            complete(ParsedMetric::Gauge(Dimension::with_name("foo"), 1500.0))
+        );
+        assert_eq!(
+            parse_gauge(&b"foo:2E-2|g"[..]),
+            complete(ParsedMetric::Gauge(Dimension::with_name("foo"), 0.02))
         )
     }
 
@@ -156,45 +368,244 @@ mod tests {
     fn it_parses_counter() {
         assert_eq!(
             parse_counter(&b"foo.bar_baz:23|c"[..]),
-            complete(ParsedMetric::Counter("foo.bar_baz".to_owned(), 23))
+            complete(ParsedMetric::Counter(Dimension::with_name("foo.bar_baz"), 23, None))
+        )
+    }
+
+    #[test]
+    fn it_parses_negative_counter() {
+        assert_eq!(
+            parse_counter(&b"foo:-4|c"[..]),
+            complete(ParsedMetric::Counter(Dimension::with_name("foo"), -4, None))
         )
     }
 
     #[test]
     fn it_parses_counter_with_sample_rate() {
         assert_eq!(
-            parse_counter(&b"foo.bar_baz:34|c|@5"[..]),
-            complete(ParsedMetric::Counter("foo.bar_baz".to_owned(), 34))
+            parse_counter(&b"foo.bar_baz:34|c|@0.1"[..]),
+            complete(ParsedMetric::Counter(Dimension::with_name("foo.bar_baz"), 34, Some(0.1)))
         )
     }
 
+    #[test]
+    fn it_parses_whole_sample_rate() {
+        assert_eq!(
+            parse_sample_rate(&b"|@1"[..]),
+            complete(1.0)
+        )
+    }
+
+    #[test]
+    fn it_rejects_out_of_range_sample_rate() {
+        assert!(parse_sample_rate(&b"|@0"[..]).is_err());
+        assert!(parse_sample_rate(&b"|@5"[..]).is_err());
+        assert!(parse_sample_rate(&b"|@-0.5"[..]).is_err());
+    }
+
     #[test]
     fn it_parses_timer() {
         assert_eq!(
             parse_timer(&b"foo.bar_baz:12|ms"[..]),
-            complete(ParsedMetric::Timer("foo.bar_baz".to_owned(), 12))
+            complete(ParsedMetric::Timer(Dimension::with_name("foo.bar_baz"), 12.0, None))
+        )
+    }
+
+    #[test]
+    fn it_parses_timer_with_sample_rate() {
+        assert_eq!(
+            parse_timer(&b"foo:12|ms|@0.25"[..]),
+            complete(ParsedMetric::Timer(Dimension::with_name("foo"), 12.0, Some(0.25)))
+        )
+    }
+
+    #[test]
+    fn it_parses_timer_with_decimal() {
+        assert_eq!(
+            parse_timer(&b"latency:12.5|ms"[..]),
+            complete(ParsedMetric::Timer(Dimension::with_name("latency"), 12.5, None))
+        )
+    }
+
+    #[test]
+    fn it_parses_histogram() {
+        assert_eq!(
+            parse_histogram(&b"foo:12.5|h|@0.5"[..]),
+            complete(ParsedMetric::Histogram(Dimension::with_name("foo"), 12.5, Some(0.5)))
+        )
+    }
+
+    #[test]
+    fn it_parses_distribution() {
+        assert_eq!(
+            parse_distribution(&b"foo:3|d"[..]),
+            complete(ParsedMetric::Distribution(Dimension::with_name("foo"), 3.0, None))
+        )
+    }
+
+    #[test]
+    fn it_parses_set() {
+        assert_eq!(
+            parse_set(&b"user_id:1234|s"[..]),
+            complete(ParsedMetric::Set(Dimension::with_name("user_id"), "1234".to_owned()))
+        );
+        assert_eq!(
+            parse_set(&b"visitor:a-b.c|s"[..]),
+            complete(ParsedMetric::Set(Dimension::with_name("visitor"), "a-b.c".to_owned()))
+        )
+    }
+
+    #[test]
+    fn it_parses_tags() {
+        let mut tags = Tags::new();
+        tags.insert("env".to_owned(), "prod".to_owned());
+        tags.insert("route".to_owned(), "/users".to_owned());
+        tags.insert("beta".to_owned(), "".to_owned());
+
+        assert_eq!(
+            parse_tags(&b"|#env:prod,route:/users,beta"[..]),
+            complete(tags)
+        );
+        assert_eq!(
+            parse_tags(&b""[..]),
+            complete(Tags::new())
         )
     }
 
+    #[test]
+    fn it_parses_metrics_with_tags() {
+        let mut tags = Tags::new();
+        tags.insert("env".to_owned(), "prod".to_owned());
+
+        let dim = Dimension { name: "foo".to_owned(), source: None, tags };
+
+        assert_eq!(
+            parse_counter(&b"foo:1|c|@0.5|#env:prod"[..]),
+            complete(ParsedMetric::Counter(dim.clone(), 1, Some(0.5)))
+        );
+        assert_eq!(
+            parse_gauge(&b"foo:1|g|#env:prod"[..]),
+            complete(ParsedMetric::Gauge(dim.clone(), 1.0))
+        );
+        assert_eq!(
+            parse_timer(&b"foo:1|ms|#env:prod"[..]),
+            complete(ParsedMetric::Timer(dim.clone(), 1.0, None))
+        );
+        assert_eq!(
+            parse_set(&b"foo:a|s|#env:prod"[..]),
+            complete(ParsedMetric::Set(dim.clone(), "a".to_owned()))
+        )
+    }
+
+    #[test]
+    fn it_rejects_malformed_values() {
+        assert!(parse_gauge(&b"foo:1.2.3|g"[..]).is_err());
+        assert!(parse_gauge(&b"foo:e|g"[..]).is_err());
+        assert!(parse_timer(&b"foo:|ms"[..]).is_err());
+    }
+
+    #[test]
+    fn it_converts_to_standard_metrics() {
+        assert_eq!(
+            ParsedMetric::Counter(Dimension::with_name("foo"), -2, None).to_standard_metric(),
+            Metric::Count(Dimension::with_name("foo"), -2)
+        );
+        assert_eq!(
+            ParsedMetric::Counter(Dimension::with_name("foo"), 1, Some(0.1)).to_standard_metric(),
+            Metric::SampledCount(Dimension::with_name("foo"), 1, 0.1)
+        );
+        assert_eq!(
+            ParsedMetric::Gauge(Dimension::with_name("foo"), -3.5).to_standard_metric(),
+            Metric::Sample(Dimension::with_name("foo"), -3.5)
+        );
+        assert_eq!(
+            ParsedMetric::GaugeDelta(Dimension::with_name("foo"), -1.0).to_standard_metric(),
+            Metric::SampleDelta(Dimension::with_name("foo"), -1.0)
+        );
+        assert_eq!(
+            ParsedMetric::Timer(Dimension::with_name("foo"), 12.5, None).to_standard_metric(),
+            Metric::Measure(Dimension::with_name("foo"), 12.5)
+        );
+        assert_eq!(
+            ParsedMetric::Timer(Dimension::with_name("foo"), 12.5, Some(0.5)).to_standard_metric(),
+            Metric::SampledMeasure(Dimension::with_name("foo"), 12.5, 0.5)
+        );
+        assert_eq!(
+            ParsedMetric::Histogram(Dimension::with_name("foo"), 1.5, None).to_standard_metric(),
+            Metric::Measure(Dimension::with_name("foo"), 1.5)
+        );
+        assert_eq!(
+            ParsedMetric::Distribution(Dimension::with_name("foo"), 2.5, None).to_standard_metric(),
+            Metric::Distribution(Dimension::with_name("foo"), 2.5)
+        );
+    }
+
     #[test]
     fn it_parse_single_metric() {
         assert_eq!(
             parse_metrics(&b"foo:1|g"[..]),
-            Ok(vec![
-                ParsedMetric::Gauge("foo".to_owned(), 1),
-            ])
+            ParsedMetrics {
+                metrics: vec![
+                    ParsedMetric::Gauge(Dimension::with_name("foo"), 1.0),
+                ],
+                events: vec![],
+                service_checks: vec![],
+                errors: vec![],
+            }
         )
     }
 
     #[test]
     fn it_parse_many_metrics() {
         assert_eq!(
-            parse_metrics(&b"foo:1|g\nbar:2|c|@3\nbaz:4|ms"[..]),
-            Ok(vec![
-                ParsedMetric::Gauge("foo".to_owned(), 1),
-                ParsedMetric::Counter("bar".to_owned(), 2),
-                ParsedMetric::Timer("baz".to_owned(), 4),
-            ])
+            parse_metrics(&b"foo:1|g\nbar:2|c|@0.5\nbaz:4|ms\nqux:abc|s"[..]),
+            ParsedMetrics {
+                metrics: vec![
+                    ParsedMetric::Gauge(Dimension::with_name("foo"), 1.0),
+                    ParsedMetric::Counter(Dimension::with_name("bar"), 2, Some(0.5)),
+                    ParsedMetric::Timer(Dimension::with_name("baz"), 4.0, None),
+                    ParsedMetric::Set(Dimension::with_name("qux"), "abc".to_owned()),
+                ],
+                events: vec![],
+                service_checks: vec![],
+                errors: vec![],
+            }
         )
     }
+
+    #[test]
+    fn it_skips_blank_lines() {
+        let parsed = parse_metrics(&b"foo:1|g\r\n\nbar:2|c\n"[..]);
+
+        assert_eq!(parsed.metrics.len(), 2);
+        assert_eq!(parsed.errors, vec![]);
+    }
+
+    #[test]
+    fn it_parses_events_and_service_checks() {
+        let parsed = parse_metrics(&b"foo:1|c\n_e{1,1}:a|b\n_sc|app|0"[..]);
+
+        assert_eq!(parsed.metrics, vec![
+            ParsedMetric::Counter(Dimension::with_name("foo"), 1, None),
+        ]);
+        assert_eq!(parsed.events, vec![Event::new("a", "b")]);
+        assert_eq!(parsed.service_checks, vec![ServiceCheck::new("app", ServiceCheckStatus::Ok)]);
+        assert_eq!(parsed.errors, vec![]);
+    }
+
+    #[test]
+    fn it_recovers_from_malformed_lines() {
+        let parsed = parse_metrics(&b"foo:1|g\nbad\nbar:2|c|@5\nbaz:4|ms"[..]);
+
+        assert_eq!(parsed.metrics, vec![
+            ParsedMetric::Gauge(Dimension::with_name("foo"), 1.0),
+            ParsedMetric::Timer(Dimension::with_name("baz"), 4.0, None),
+        ]);
+
+        assert_eq!(parsed.errors.len(), 2);
+        assert_eq!(parsed.errors[0].line, "bad");
+        assert_eq!(parsed.errors[0].offset, 8);
+        assert_eq!(parsed.errors[1].line, "bar:2|c|@5");
+        assert_eq!(parsed.errors[1].offset, 12);
+    }
 }
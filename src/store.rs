@@ -1,25 +1,135 @@
-use std::collections::HashMap;
-use std::sync::{mpsc, Arc, Mutex};
+use std::collections::{HashMap, HashSet};
+use std::error;
+use std::fmt;
+use std::sync::{mpsc, Arc, Mutex, RwLock};
 use std::thread::{self, sleep, JoinHandle};
 use std::time::Duration;
 
-use metrics::*;
+use crate::forwarders::Relay;
+use crate::hyperloglog::{self, HyperLogLog};
+use crate::metrics::*;
+
+/// How a store keeps track of the distinct values in sets.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SetMode {
+    /// Remember every distinct value. If a limit is given then values past
+    /// that many will be ignored and the unique count stops growing there.
+    Exact(Option<usize>),
+    /// Estimate the unique count with a HyperLogLog of the given precision
+    /// (4 to 16). Memory use is fixed at `2^precision` bytes per set.
+    HyperLogLog(u8),
+}
+
+/// Error from creating a store with a `SetMode` it can't use.
+#[derive(Debug, PartialEq)]
+pub struct InvalidSetMode(pub SetMode);
+
+impl fmt::Display for InvalidSetMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid set mode {:?}: HyperLogLog precision must be between 4 and 16", self.0)
+    }
+}
+
+impl error::Error for InvalidSetMode {}
+
+enum SetValues {
+    Exact(HashSet<String>, Option<usize>),
+    Estimated(HyperLogLog),
+}
+
+impl SetValues {
+    fn new(mode: SetMode) -> SetValues {
+        match mode {
+            SetMode::Exact(limit) => SetValues::Exact(HashSet::new(), limit),
+            SetMode::HyperLogLog(precision) => SetValues::Estimated(HyperLogLog::new(precision)),
+        }
+    }
+
+    fn insert(&mut self, value: String) {
+        match *self {
+            SetValues::Exact(ref mut values, limit) => {
+                if let Some(limit) = limit {
+                    if values.len() >= limit { return }
+                }
+                values.insert(value);
+            },
+            SetValues::Estimated(ref mut hll) => hll.insert(&value),
+        }
+    }
+
+    fn count(&self) -> f64 {
+        match *self {
+            SetValues::Exact(ref values, _) => values.len() as f64,
+            SetValues::Estimated(ref hll) => hll.count().round(),
+        }
+    }
+}
 
 /// Internal storage of metrics data. Normally you will want a `SharedStore`
 /// which wraps this in an `Arc<Mutex<BaseStore>>` for thread-safe sharing
 /// and access.
 pub struct BaseStore {
-    counts: HashMap<Dimension, u64>,
+    /// Number of flushes a sample's last value is remembered for after it
+    /// was last recorded, so that `SampleDelta`s can adjust it. Without a
+    /// limit every sample ever seen is kept for the life of the store, which
+    /// grows without bound with high-cardinality tags; with one, a sample
+    /// that's only adjusted less often than that starts over from 0.
+    /// Defaults to 60 flushes.
+    pub sample_expiry: Option<u64>,
+    counts: HashMap<Dimension, f64>,
     measures: HashMap<Dimension, Vec<f64>>,
+    /// How many measures each dimension's values stand for; this differs
+    /// from the number of values when measures are sampled.
+    measure_counts: HashMap<Dimension, f64>,
     samples: HashMap<Dimension, f64>,
+    /// Most recent value of every sample recorded within the last
+    /// `sample_expiry` flushes, along with the flush it was recorded in.
+    /// Unlike `samples` this isn't cleared on flush so that sample deltas can
+    /// be applied across flush intervals.
+    last_samples: HashMap<Dimension, (f64, u64)>,
+    /// Number of times the store has been flushed.
+    flushes: u64,
+    /// Timestamp of each of this interval's samples that had one.
+    sample_timestamps: HashMap<Dimension, i64>,
+    sets: HashMap<Dimension, SetValues>,
+    set_mode: SetMode,
+    distributions: HashMap<Dimension, Vec<f64>>,
+    events: Vec<Event>,
+    service_checks: Vec<ServiceCheck>,
 }
 
 impl BaseStore {
     pub fn new() -> BaseStore {
+        BaseStore::empty(SetMode::Exact(None))
+    }
+
+    /// Creates a store that tracks sets' values the given way. The mode is
+    /// checked here rather than when the first set is recorded.
+    pub fn with_set_mode(set_mode: SetMode) -> Result<BaseStore, InvalidSetMode> {
+        if let SetMode::HyperLogLog(precision) = set_mode {
+            if !hyperloglog::PRECISIONS.contains(&precision) {
+                return Err(InvalidSetMode(set_mode))
+            }
+        }
+
+        Ok(BaseStore::empty(set_mode))
+    }
+
+    fn empty(set_mode: SetMode) -> BaseStore {
         BaseStore {
+            sample_expiry: Some(60),
             counts: HashMap::new(),
             measures: HashMap::new(),
+            measure_counts: HashMap::new(),
             samples: HashMap::new(),
+            last_samples: HashMap::new(),
+            flushes: 0,
+            sample_timestamps: HashMap::new(),
+            sets: HashMap::new(),
+            set_mode,
+            distributions: HashMap::new(),
+            events: vec![],
+            service_checks: vec![],
         }
     }
 
@@ -27,35 +137,112 @@ impl BaseStore {
         for metric in metrics {
             match metric {
                 Count(dim, value) => {
-                    let count = self.counts.entry(dim).or_insert(0);
-                    *count += value;
+                    self.record_count(dim, value as f64)
+                },
+                SampledCount(dim, value, rate) => {
+                    self.record_count(dim, value as f64 / rate)
                 },
                 Measure(dim, value) => {
-                    let values = self.measures.entry(dim).or_insert(Vec::new());
-                    values.push(value);
+                    self.record_measure(dim, value, 1.0)
+                },
+                SampledMeasure(dim, value, rate) => {
+                    self.record_measure(dim, value, 1.0 / rate)
                 },
                 Sample(dim, value) => {
-                    let entry = self.samples.entry(dim).or_insert(0.0);
-                    *entry = value;
-                }
+                    self.record_sample(dim, value)
+                },
+                SampleDelta(dim, delta) => {
+                    let value = self.last_samples.get(&dim).map_or(0.0, |&(value, _)| value) + delta;
+                    self.record_sample(dim, value)
+                },
+                TimestampedSample(dim, value, timestamp) => {
+                    if let Some(&newest) = self.sample_timestamps.get(&dim) {
+                        if timestamp < newest { continue }
+                    }
+                    self.sample_timestamps.insert(dim.clone(), timestamp);
+                    self.record_sample(dim, value)
+                },
+                Set(dim, value) => {
+                    let mode = self.set_mode;
+                    let values = self.sets.entry(dim).or_insert_with(|| SetValues::new(mode));
+                    values.insert(value);
+                },
+                Distribution(dim, value) => {
+                    let values = self.distributions.entry(dim).or_default();
+                    values.push(value);
+                },
             }
         }
     } // fn record
 
+    pub fn record_events(&mut self, events: Vec<Event>) {
+        self.events.extend(events)
+    }
+
+    pub fn record_service_checks(&mut self, service_checks: Vec<ServiceCheck>) {
+        self.service_checks.extend(service_checks)
+    }
+
+    fn record_count(&mut self, dim: Dimension, value: f64) {
+        let count = self.counts.entry(dim).or_insert(0.0);
+        *count += value;
+    }
+
+    fn record_sample(&mut self, dim: Dimension, value: f64) {
+        self.last_samples.insert(dim.clone(), (value, self.flushes));
+        self.samples.insert(dim, value);
+    }
+
+    fn record_measure(&mut self, dim: Dimension, value: f64, weight: f64) {
+        let count = self.measure_counts.entry(dim.clone()).or_insert(0.0);
+        *count += weight;
+
+        let values = self.measures.entry(dim).or_default();
+        values.push(value);
+    }
+
+    /// Aggregates everything recorded in this interval, along with its
+    /// events and service checks, and starts a new interval.
     pub fn flush(&mut self) -> AggregatedMetrics {
-        let mut aggregated = AggregatedMetrics::new();
+        let mut aggregated = self.snapshot();
 
-        aggregated.aggregate_counts(self.counts.iter());
-        self.counts.clear();
+        aggregated.add_events(self.events.drain(..));
+        aggregated.add_service_checks(self.service_checks.drain(..));
 
-        aggregated.aggregate_measures(self.measures.iter());
+        self.counts.clear();
         self.measures.clear();
-
-        aggregated.aggregate_samples(self.samples.iter());
+        self.measure_counts.clear();
         self.samples.clear();
+        self.sample_timestamps.clear();
+        self.sets.clear();
+        self.distributions.clear();
+
+        self.flushes += 1;
+        if let Some(expiry) = self.sample_expiry {
+            let flushes = self.flushes;
+            self.last_samples.retain(|_, &mut (_, recorded)| flushes - recorded <= expiry);
+        }
 
         aggregated
     } // fn flush
+
+    /// Aggregates the metrics recorded so far in this interval without
+    /// clearing them, eg. for an endpoint that's scraped between flushes.
+    /// Events and service checks are left for `flush`.
+    pub fn snapshot(&self) -> AggregatedMetrics {
+        let mut aggregated = AggregatedMetrics::new();
+
+        aggregated.aggregate_counts(self.counts.iter());
+        aggregated.aggregate_measures(self.measures.iter().map(|(dim, values)| {
+            let count = self.measure_counts.get(dim).cloned().unwrap_or(values.len() as f64);
+            (dim, values, count)
+        }));
+        aggregated.aggregate_samples(self.samples.iter());
+        aggregated.aggregate_sets(self.sets.iter().map(|(dim, values)| (dim.clone(), values.count())));
+        aggregated.aggregate_distributions(self.distributions.iter().map(|(dim, values)| (dim.clone(), values.clone())));
+
+        aggregated
+    }
 }
 
 /// Thread-safe interface to the store. In most cases this is what you
@@ -63,21 +250,51 @@ impl BaseStore {
 #[derive(Clone)]
 pub struct SharedStore {
     shared: Arc<Mutex<BaseStore>>,
+    relays: Arc<RwLock<Vec<Arc<dyn Relay>>>>,
 }
 
 impl SharedStore {
     pub fn new() -> SharedStore {
+        SharedStore::with_base_store(BaseStore::new())
+    }
+
+    /// Wraps an already configured `BaseStore`.
+    pub fn with_base_store(store: BaseStore) -> SharedStore {
         SharedStore {
-            shared: Arc::new(Mutex::new(BaseStore::new())),
+            shared: Arc::new(Mutex::new(store)),
+            relays: Arc::new(RwLock::new(vec![])),
         }
     }
 
-    /// Takes a `Vec` of metrics and stores them.
+    /// Passes every metric recorded from now on to `relay` as well. Clones of
+    /// the store share their relays.
+    pub fn add_relay(&self, relay: Arc<dyn Relay>) {
+        self.relays.write().unwrap().push(relay)
+    }
+
+    /// Takes a `Vec` of metrics and stores them, after passing them to any
+    /// relays.
     pub fn record(&self, metrics: Vec<Metric>) {
+        for relay in self.relays.read().unwrap().iter() {
+            relay.relay(&metrics)
+        }
+
         let mut store = self.shared.lock().unwrap();
         store.record(metrics)
     }
 
+    /// Takes a `Vec` of events and stores them until the next flush.
+    pub fn record_events(&self, events: Vec<Event>) {
+        let mut store = self.shared.lock().unwrap();
+        store.record_events(events)
+    }
+
+    /// Takes a `Vec` of service checks and stores them until the next flush.
+    pub fn record_service_checks(&self, service_checks: Vec<ServiceCheck>) {
+        let mut store = self.shared.lock().unwrap();
+        store.record_service_checks(service_checks)
+    }
+
     /// Aggregates all the metrics currently in the store and returns an
     /// `AggregatedMetrics` with the aggregated values for those metrics.
     /// This will empty the store, so it will not have any metrics in it
@@ -87,9 +304,15 @@ impl SharedStore {
         store.flush()
     }
 
+    /// Aggregates the metrics currently in the store without emptying it.
+    pub fn snapshot(&self) -> AggregatedMetrics {
+        let store = self.shared.lock().unwrap();
+        store.snapshot()
+    }
+
     /// Starts a thread that calls `flush` on itself at a certain rate. After
     /// flushing it calls the given callback with the aggregated metrics
-    /// that were flushed.
+    /// that were flushed, eg. `move |metrics| forwarder.forward(metrics)`.
     pub fn flush_every<F>(&self, interval: Duration, callback: F) -> Vec<JoinHandle<()>>
         where F: Fn(AggregatedMetrics) + Send + 'static {
 
@@ -124,10 +347,20 @@ impl SharedStore {
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
 
-    use super::BaseStore;
+    use super::{BaseStore, InvalidSetMode, SetMode, SharedStore};
+    use super::super::forwarders::Relay;
     use super::super::metrics::*;
 
+    struct RecordingRelay(Mutex<Vec<Metric>>);
+
+    impl Relay for RecordingRelay {
+        fn relay(&self, metrics: &[Metric]) {
+            self.0.lock().unwrap().extend_from_slice(metrics)
+        }
+    }
+
     fn get_store_with_metrics() -> BaseStore {
         let metrics = vec![
             Count(Dimension::with_name("foo"), 1),
@@ -150,7 +383,7 @@ mod tests {
         let store = get_store_with_metrics();
 
         let mut expected_counts = HashMap::new();
-        expected_counts.insert(Dimension::with_name("foo"), 3);
+        expected_counts.insert(Dimension::with_name("foo"), 3.0);
 
         assert_eq!(store.counts, expected_counts)
     }
@@ -174,4 +407,215 @@ mod tests {
 
         assert_eq!(store.samples, expected_samples)
     }
+
+    #[test]
+    fn it_applies_sample_deltas() {
+        let mut store = get_store_with_metrics();
+        store.record(vec![
+            SampleDelta(Dimension::with_name("baz"), -1.5),
+            SampleDelta(Dimension::with_name("qux"), 2.0),
+        ]);
+
+        let mut expected_samples = HashMap::new();
+        expected_samples.insert(Dimension::with_name("baz"), 7.5);
+        expected_samples.insert(Dimension::with_name("qux"), 2.0);
+
+        assert_eq!(store.samples, expected_samples)
+    }
+
+    #[test]
+    fn it_applies_sample_deltas_across_flushes() {
+        let mut store = get_store_with_metrics();
+        store.flush();
+        store.record(vec![
+            SampleDelta(Dimension::with_name("baz"), 1.0),
+        ]);
+
+        let mut expected_samples = HashMap::new();
+        expected_samples.insert(Dimension::with_name("baz"), 10.0);
+
+        assert_eq!(store.samples, expected_samples)
+    }
+
+    #[test]
+    fn it_forgets_samples_that_havent_been_recorded_for_a_while() {
+        let mut store = BaseStore::new();
+        store.sample_expiry = Some(2);
+        store.record(vec![
+            Sample(Dimension::with_name("foo"), 5.0),
+            Sample(Dimension::with_name("bar"), 5.0),
+        ]);
+        store.flush();
+        store.flush();
+        store.record(vec![SampleDelta(Dimension::with_name("foo"), 1.0)]);
+        store.flush();
+        store.record(vec![
+            SampleDelta(Dimension::with_name("foo"), 1.0),
+            SampleDelta(Dimension::with_name("bar"), 1.0),
+        ]);
+
+        let mut expected_samples = HashMap::new();
+        expected_samples.insert(Dimension::with_name("foo"), 7.0);
+        expected_samples.insert(Dimension::with_name("bar"), 1.0);
+
+        assert_eq!(store.samples, expected_samples);
+        assert_eq!(store.last_samples.len(), 2);
+    }
+
+    #[test]
+    fn it_keeps_the_newest_timestamped_sample() {
+        let mut store = BaseStore::new();
+        store.record(vec![
+            TimestampedSample(Dimension::with_name("load"), 2.0, 1500000060),
+            TimestampedSample(Dimension::with_name("load"), 1.0, 1500000000),
+        ]);
+
+        let mut expected_samples = HashMap::new();
+        expected_samples.insert(Dimension::with_name("load"), 2.0);
+
+        assert_eq!(store.samples, expected_samples);
+
+        store.flush();
+        store.record(vec![
+            TimestampedSample(Dimension::with_name("load"), 3.0, 1500000000),
+        ]);
+        expected_samples.insert(Dimension::with_name("load"), 3.0);
+
+        assert_eq!(store.samples, expected_samples)
+    }
+
+    #[test]
+    fn it_snapshots_without_clearing() {
+        let store = get_store_with_metrics();
+
+        let snapshot = store.snapshot();
+        assert!(snapshot.iter().any(|m| *m == (AggregatedMetricType::Count, Dimension::with_name("foo"), 3.0)));
+        assert_eq!(store.snapshot(), snapshot);
+        assert_eq!(store.counts.len(), 1);
+    }
+
+    #[test]
+    fn it_passes_recorded_metrics_to_relays() {
+        let store = SharedStore::new();
+        let relay = Arc::new(RecordingRelay(Mutex::new(vec![])));
+        store.clone().add_relay(relay.clone());
+
+        store.record(vec![Count(Dimension::with_name("foo"), 1)]);
+
+        assert_eq!(*relay.0.lock().unwrap(), vec![Count(Dimension::with_name("foo"), 1)]);
+        assert_eq!(store.flush().len(), 1);
+    }
+
+    #[test]
+    fn it_aggregates_set_unique_counts() {
+        let mut store = BaseStore::new();
+        store.record(vec![
+            Set(Dimension::with_name("users"), "1".to_owned()),
+            Set(Dimension::with_name("users"), "2".to_owned()),
+            Set(Dimension::with_name("users"), "1".to_owned()),
+        ]);
+
+        assert_eq!(store.flush(), AggregatedMetrics::with_metrics(vec![
+            (AggregatedMetricType::Set, Dimension::with_name("users"), 2.0),
+        ]));
+        assert_eq!(store.flush(), AggregatedMetrics::new());
+    }
+
+    #[test]
+    fn it_caps_exact_sets() {
+        let mut store = BaseStore::with_set_mode(SetMode::Exact(Some(2))).unwrap();
+        store.record(vec![
+            Set(Dimension::with_name("users"), "1".to_owned()),
+            Set(Dimension::with_name("users"), "2".to_owned()),
+            Set(Dimension::with_name("users"), "3".to_owned()),
+        ]);
+
+        assert_eq!(store.flush(), AggregatedMetrics::with_metrics(vec![
+            (AggregatedMetricType::Set, Dimension::with_name("users"), 2.0),
+        ]));
+    }
+
+    #[test]
+    fn it_estimates_hyperloglog_sets() {
+        let mut store = BaseStore::with_set_mode(SetMode::HyperLogLog(12)).unwrap();
+        store.record(vec![
+            Set(Dimension::with_name("users"), "1".to_owned()),
+            Set(Dimension::with_name("users"), "2".to_owned()),
+            Set(Dimension::with_name("users"), "1".to_owned()),
+        ]);
+
+        assert_eq!(store.flush(), AggregatedMetrics::with_metrics(vec![
+            (AggregatedMetricType::Set, Dimension::with_name("users"), 2.0),
+        ]));
+    }
+
+    #[test]
+    fn it_rejects_out_of_range_hyperloglog_precisions() {
+        assert!(BaseStore::with_set_mode(SetMode::HyperLogLog(4)).is_ok());
+        assert_eq!(BaseStore::with_set_mode(SetMode::HyperLogLog(20)).err(), Some(InvalidSetMode(SetMode::HyperLogLog(20))));
+        assert!(BaseStore::with_set_mode(SetMode::HyperLogLog(3)).is_err());
+    }
+
+    #[test]
+    fn it_passes_distribution_points_through() {
+        let mut store = BaseStore::new();
+        store.record(vec![
+            Distribution(Dimension::with_name("latency"), 1.0),
+            Distribution(Dimension::with_name("latency"), 3.0),
+        ]);
+
+        let aggregated = store.flush();
+        assert_eq!(aggregated.len(), 0);
+        assert_eq!(
+            aggregated.distributions().collect::<Vec<_>>(),
+            vec![&(Dimension::with_name("latency"), vec![1.0, 3.0])]
+        );
+    }
+
+    #[test]
+    fn it_flushes_events_and_service_checks() {
+        let mut store = BaseStore::new();
+        store.record_events(vec![Event::new("Deploy", "v1.2")]);
+        store.record_service_checks(vec![ServiceCheck::new("app", ServiceCheckStatus::Ok)]);
+
+        let aggregated = store.flush();
+        assert_eq!(aggregated.events(), &[Event::new("Deploy", "v1.2")]);
+        assert_eq!(aggregated.service_checks(), &[ServiceCheck::new("app", ServiceCheckStatus::Ok)]);
+
+        let aggregated = store.flush();
+        assert!(aggregated.events().is_empty());
+        assert!(aggregated.service_checks().is_empty());
+    }
+
+    #[test]
+    fn it_scales_sampled_counts() {
+        let mut store = BaseStore::new();
+        store.record(vec![
+            Count(Dimension::with_name("foo"), 1),
+            SampledCount(Dimension::with_name("foo"), 1, 0.1),
+            SampledCount(Dimension::with_name("foo"), 2, 0.5),
+        ]);
+
+        let mut expected_counts = HashMap::new();
+        expected_counts.insert(Dimension::with_name("foo"), 15.0);
+
+        assert_eq!(store.counts, expected_counts)
+    }
+
+    #[test]
+    fn it_weights_sampled_measures() {
+        let mut store = BaseStore::new();
+        store.record(vec![
+            Measure(Dimension::with_name("bar"), 1.0),
+            SampledMeasure(Dimension::with_name("bar"), 2.0, 0.25),
+        ]);
+
+        let mut expected_measures = HashMap::new();
+        expected_measures.insert(Dimension::with_name("bar"), vec![1.0, 2.0]);
+        assert_eq!(store.measures, expected_measures);
+
+        let aggregated = store.flush();
+        let count = aggregated.iter().find(|m| m.1.name == "bar.count").map(|m| m.2);
+        assert_eq!(count, Some(5.0));
+    }
 }